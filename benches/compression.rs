@@ -0,0 +1,169 @@
+//! Compression ratio and speed on Kore link traffic.
+//!
+//!     cargo bench --bench compression [capture]
+//!
+//! `capture` is a recording of the raw bytes sent to Kore by a build without
+//! compression, for example the DLL's side of the Kore connection saved as
+//! raw data from Wireshark's "Follow TCP Stream". Without one, traffic is
+//! synthesized from packets a busy town map produces: actors walking,
+//! appearing and vanishing, and public chat. The synthetic numbers are a
+//! guide only; a real capture gives the figures that matter.
+//!
+//! Figures from the synthetic traffic (no capture has been measured yet;
+//! add a capture's figures here when one is):
+//!
+//!     synthetic town traffic: 632085 bytes
+//!       bursts of   1024 bytes:    407396 bytes, ratio 1.55, 369 MiB/s
+//!       bursts of   8192 bytes:    331453 bytes, ratio 1.91, 376 MiB/s
+//!       bursts of  65536 bytes:    318139 bytes, ratio 1.99, 384 MiB/s
+
+use std::time::Instant;
+
+use netredirect_rust::compress;
+use netredirect_rust::frame::HEADER_LEN;
+use netredirect_rust::XKoreFrame;
+
+/// How often each run is repeated for the throughput figure.
+const ROUNDS: usize = 20;
+
+/// A small deterministic generator, so runs are comparable.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as u32
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        self.next() % n
+    }
+}
+
+/// Packs a map coordinate pair the way the RO protocol does.
+fn position(rng: &mut Rng) -> [u8; 3] {
+    let (x, y) = (rng.below(300) as u16, rng.below(300) as u16);
+    [(x >> 2) as u8, ((x << 6) as u8) | ((y >> 4) as u8), (y << 4) as u8]
+}
+
+fn synthesize() -> Vec<u8> {
+    const ACTORS: u32 = 200;
+    const NAMES: [&str; 6] = ["Poring", "Lunatic", "Acolyte", "Merchant", "Swordman", "Novice"];
+    const CHAT: [&str; 5] = ["S> Elu 10k each", "B> Oridecon", "party for orc dungeon?", "hi", "@go 0"];
+
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut stream = Vec::new();
+    let mut tick: u32 = 1_000_000;
+    for _ in 0..20_000 {
+        tick += rng.below(40);
+        let id = 110_000_000 + rng.below(ACTORS);
+        let mut packet = Vec::new();
+        match rng.below(10) {
+            // Actor moved.
+            0..=5 => {
+                packet.extend_from_slice(&0x0086u16.to_le_bytes());
+                packet.extend_from_slice(&id.to_le_bytes());
+                packet.extend_from_slice(&position(&mut rng));
+                packet.extend_from_slice(&position(&mut rng));
+                packet.extend_from_slice(&[0x88]);
+                packet.extend_from_slice(&tick.to_le_bytes());
+            }
+            // Actor appeared.
+            6 => {
+                packet.extend_from_slice(&0x09ffu16.to_le_bytes());
+                packet.extend_from_slice(&[0; 2]);
+                packet.push(0);
+                packet.extend_from_slice(&id.to_le_bytes());
+                packet.extend_from_slice(&150u16.to_le_bytes());
+                packet.extend_from_slice(&[0; 24]);
+                packet.extend_from_slice(&(1000 + rng.below(50) as u16).to_le_bytes());
+                packet.extend_from_slice(&[0; 30]);
+                packet.extend_from_slice(&position(&mut rng));
+                packet.extend_from_slice(&[5, 5, 0, 0]);
+                packet.extend_from_slice(&(rng.below(99) as u16 + 1).to_le_bytes());
+                packet.extend_from_slice(&[0; 8]);
+                let name = NAMES[rng.below(NAMES.len() as u32) as usize];
+                let mut field = [0u8; 24];
+                field[..name.len()].copy_from_slice(name.as_bytes());
+                packet.extend_from_slice(&field);
+                let len = packet.len() as u16;
+                packet[2..4].copy_from_slice(&len.to_le_bytes());
+            }
+            // Actor vanished.
+            7 => {
+                packet.extend_from_slice(&0x0080u16.to_le_bytes());
+                packet.extend_from_slice(&id.to_le_bytes());
+                packet.push(rng.below(2) as u8);
+            }
+            // Public chat.
+            _ => {
+                let name = NAMES[rng.below(NAMES.len() as u32) as usize];
+                let text = format!("{} : {}\0", name, CHAT[rng.below(CHAT.len() as u32) as usize]);
+                packet.extend_from_slice(&0x008du16.to_le_bytes());
+                packet.extend_from_slice(&((8 + text.len()) as u16).to_le_bytes());
+                packet.extend_from_slice(&id.to_le_bytes());
+                packet.extend_from_slice(text.as_bytes());
+            }
+        }
+        XKoreFrame::Received(packet).encode_into(&mut stream);
+
+        // The client answers now and then, mostly with moves and syncs.
+        if rng.below(8) == 0 {
+            let mut packet = 0x035fu16.to_le_bytes().to_vec();
+            packet.extend_from_slice(&position(&mut rng));
+            XKoreFrame::Sent(packet).encode_into(&mut stream);
+        }
+    }
+    stream
+}
+
+fn main() {
+    let capture = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let (source, stream) = match capture {
+        Some(path) => {
+            let data = std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+            (path, data)
+        }
+        None => ("synthetic town traffic".to_string(), synthesize()),
+    };
+
+    // The relay compresses whatever is queued at the time, so try a few
+    // sizes of burst.
+    println!("{}: {} bytes", source, stream.len());
+    for burst in [1024, 8 * 1024, 64 * 1024] {
+        let mut compressed = Vec::new();
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            compressed.clear();
+            for run in bursts(&stream, burst) {
+                compress::compress_into(run, &mut compressed);
+            }
+        }
+        let elapsed = start.elapsed();
+        let throughput = (stream.len() * ROUNDS) as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+        println!(
+            "  bursts of {:>6} bytes: {:>9} bytes, ratio {:.2}, {:.0} MiB/s",
+            burst,
+            compressed.len(),
+            stream.len() as f64 / compressed.len() as f64,
+            throughput
+        );
+    }
+}
+
+/// Splits `stream` at frame boundaries into runs of roughly `size` bytes.
+fn bursts(stream: &[u8], size: usize) -> Vec<&[u8]> {
+    let mut runs = Vec::new();
+    let (mut start, mut end) = (0, 0);
+    while end + HEADER_LEN <= stream.len() {
+        end = (end + HEADER_LEN + u16::from_le_bytes([stream[end + 1], stream[end + 2]]) as usize).min(stream.len());
+        if end - start >= size {
+            runs.push(&stream[start..end]);
+            start = end;
+        }
+    }
+    runs.push(&stream[start..]);
+    runs
+}
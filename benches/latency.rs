@@ -0,0 +1,99 @@
+//! How long captured traffic takes to reach Kore, and what an idle relay
+//! costs.
+//!
+//!     cargo bench --bench latency
+//!
+//! A local stand-in for Kore measures the time from `capture_recv` to the
+//! frame arriving on its socket, one packet at a time so every sample sees
+//! an idle relay. On Linux the CPU time the process uses while nothing is
+//! captured is reported as well.
+
+use std::io::{self, Read};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use netredirect_rust::frame::FrameDecoder;
+use netredirect_rust::relay;
+use netredirect_rust::state::{capture_recv, RawSocket};
+use netredirect_rust::{Config, LinkState, NetworkState, SocketHooks, XKoreFrame};
+
+const SAMPLES: usize = 500;
+#[cfg(target_os = "linux")]
+const IDLE_TIME: Duration = Duration::from_secs(2);
+
+struct NoServer;
+
+impl SocketHooks for NoServer {
+    fn send(&self, _socket: RawSocket, data: &[u8]) -> io::Result<usize> {
+        Ok(data.len())
+    }
+}
+
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = Config { kore_port: listener.local_addr().unwrap().port(), ..Config::default() };
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), Arc::new(NoServer));
+
+    let (mut kore, _) = listener.accept().unwrap();
+    kore.set_nodelay(true).unwrap();
+    while state.lock().unwrap().link_state() != LinkState::Connected {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0u8; 256];
+    let mut samples = Vec::with_capacity(SAMPLES);
+    for i in 0..SAMPLES {
+        // Let the relay settle into whatever it does when idle.
+        thread::sleep(Duration::from_millis(3));
+        let packet = (i as u32).to_le_bytes();
+        let start = Instant::now();
+        capture_recv(&mut state.lock().unwrap(), 5, &packet);
+        loop {
+            match decoder.next_frame() {
+                Some(XKoreFrame::Received(data)) if data == packet => break,
+                Some(_) => {}
+                None => {
+                    let n = kore.read(&mut buf).unwrap();
+                    decoder.extend(&buf[..n]);
+                }
+            }
+        }
+        samples.push(start.elapsed());
+    }
+    samples.sort();
+
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100].as_secs_f64() * 1e6;
+    println!("capture to Kore over {} packets:", SAMPLES);
+    println!("  median {:>8.0} us", percentile(50));
+    println!("  p90    {:>8.0} us", percentile(90));
+    println!("  p99    {:>8.0} us", percentile(99));
+    println!("  max    {:>8.0} us", percentile(100));
+
+    #[cfg(target_os = "linux")]
+    {
+        let before = cpu_time();
+        thread::sleep(IDLE_TIME);
+        let used = cpu_time() - before;
+        println!(
+            "idle CPU over {} s: {:.1} ms ({:.2}%)",
+            IDLE_TIME.as_secs(),
+            used.as_secs_f64() * 1e3,
+            used.as_secs_f64() / IDLE_TIME.as_secs_f64() * 100.0
+        );
+    }
+
+    relay.shutdown(Duration::from_secs(5));
+}
+
+/// CPU time the process has used so far.
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    let timeval = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    timeval(usage.ru_utime) + timeval(usage.ru_stime)
+}
@@ -0,0 +1,88 @@
+//! A stand-in game client for trying out the preload backend.
+//!
+//!     cargo build --features preload --example preload_client
+//!     LD_PRELOAD=target/debug/libnetredirect_rust.so target/debug/examples/preload_client
+//!
+//! It reads one command per line on stdin and reports what each did on
+//! stderr. Data is given and reported in hex.
+//!
+//! - `connect <port>` connects to an RO server on localhost.
+//! - `recv <len>`, `peek <len>` and `read <len>` read from it with `recv`,
+//!   `recv` with `MSG_PEEK` and `read`.
+//! - `send <hex>` and `write <hex>` send to it with `send` and `write`.
+//! - `fork <hex>` sends from a forked child, which then exits.
+//!
+//! The client exits at the end of its input.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use std::io::{self, BufRead, Read, Write};
+    use std::net::TcpStream;
+    use std::os::unix::io::AsRawFd;
+
+    let mut server: Option<TcpStream> = None;
+    for line in io::stdin().lock().lines() {
+        let line = line.unwrap();
+        let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+        let stream = server.as_mut();
+        let result = match command {
+            "connect" => {
+                server = Some(TcpStream::connect(("127.0.0.1", arg.parse::<u16>().unwrap())).unwrap());
+                String::new()
+            }
+            "recv" | "peek" | "read" => {
+                let (stream, mut buf) = (stream.unwrap(), vec![0u8; arg.parse().unwrap()]);
+                let n = match command {
+                    "recv" => stream.read(&mut buf).unwrap(),
+                    "peek" => stream.peek(&mut buf).unwrap(),
+                    _ => {
+                        let ret = unsafe { libc::read(stream.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+                        usize::try_from(ret).unwrap()
+                    }
+                };
+                hex(&buf[..n])
+            }
+            "send" => {
+                stream.unwrap().write_all(&unhex(arg)).unwrap();
+                String::new()
+            }
+            "write" => {
+                let data = unhex(arg);
+                let ret = unsafe { libc::write(stream.unwrap().as_raw_fd(), data.as_ptr() as *const libc::c_void, data.len()) };
+                assert_eq!(usize::try_from(ret).unwrap(), data.len());
+                String::new()
+            }
+            "fork" => {
+                let stream = stream.unwrap();
+                match unsafe { libc::fork() } {
+                    0 => {
+                        stream.write_all(&unhex(arg)).unwrap();
+                        std::process::exit(0);
+                    }
+                    pid => {
+                        let mut status = 0;
+                        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                        status.to_string()
+                    }
+                }
+            }
+            _ => panic!("unknown command `{}`", command),
+        };
+        eprintln!("{} {}", command, result);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(target_os = "linux")]
+fn unhex(text: &str) -> Vec<u8> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("The preload backend is Linux only");
+}
@@ -0,0 +1,54 @@
+//! Exponential backoff with jitter for reconnecting to Kore.
+
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Reconnect delays that double after every failed attempt up to a ceiling.
+///
+/// Each delay is shortened by a random fraction of up to `jitter`, so many
+/// bot instances losing the same OpenKore do not all come back in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+    attempt: u32,
+    rng: u64,
+}
+
+impl Backoff {
+    /// `jitter` is clamped to `0.0..=1.0`.
+    pub fn new(initial: Duration, max: Duration, jitter: f64) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seed = now.as_nanos() as u64 ^ ((process::id() as u64) << 32);
+        Backoff {
+            initial,
+            max: max.max(initial),
+            jitter: jitter.clamp(0.0, 1.0),
+            attempt: 0,
+            rng: seed | 1,
+        }
+    }
+
+    /// The delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let factor = 2u32.saturating_pow(self.attempt.min(31));
+        let delay = self.initial.saturating_mul(factor).min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay.mul_f64(1.0 - self.jitter * self.next_random())
+    }
+
+    /// Starts over from the initial delay, after a successful connect.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// A uniformly distributed value in `0.0..1.0` (xorshift64).
+    fn next_random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,66 @@
+//! LZ4 compression of the Kore link.
+//!
+//! Once both sides agreed on [`Features::COMPRESSION`](crate::handshake::Features),
+//! runs of encoded frames may be sent wrapped in a `Z` frame whose payload is
+//! `[u32 length][LZ4 block]` of at most [`MAX_CHUNK`] bytes of the original
+//! stream. A chunk always holds whole frames but never another `Z` frame,
+//! and the receiver puts the decompressed bytes back into its stream where
+//! the `Z` frame was.
+
+use crate::frame::{XKoreFrame, HEADER_LEN};
+
+/// How many bytes of frames one `Z` frame covers. Even incompressible data
+/// then fits the 16-bit frame length after LZ4's worst-case growth.
+pub const MAX_CHUNK: usize = 60000;
+
+/// Compresses a run of encoded frames into `out`.
+///
+/// Frames are packed into chunks of at most [`MAX_CHUNK`] bytes; a single
+/// frame larger than that is written as it is. So are chunks that do not get
+/// smaller, so Kore never has to decompress what compression did not help
+/// with.
+pub fn compress_into(data: &[u8], out: &mut Vec<u8>) {
+    let mut start = 0;
+    while start < data.len() {
+        let mut end = start;
+        while end < data.len() {
+            let next = end + frame_len(&data[end..]);
+            if next - start > MAX_CHUNK && end > start {
+                break;
+            }
+            end = next;
+        }
+        write_chunk(&data[start..end], out);
+        start = end;
+    }
+}
+
+fn write_chunk(chunk: &[u8], out: &mut Vec<u8>) {
+    if chunk.len() <= MAX_CHUNK {
+        let compressed = lz4_flex::compress_prepend_size(chunk);
+        if compressed.len() + HEADER_LEN < chunk.len() {
+            XKoreFrame::Compressed(compressed).encode_into(out);
+            return;
+        }
+    }
+    out.extend_from_slice(chunk);
+}
+
+/// The encoded length of the frame at the start of `data`, or all of `data`
+/// if it does not hold a whole frame.
+fn frame_len(data: &[u8]) -> usize {
+    if data.len() < HEADER_LEN {
+        return data.len();
+    }
+    let len = HEADER_LEN + u16::from_le_bytes([data[1], data[2]]) as usize;
+    len.min(data.len())
+}
+
+/// The bytes a `Z` frame stands for, or `None` if the payload is corrupt or
+/// claims to cover more than [`MAX_CHUNK`] bytes.
+pub fn decompress(payload: &[u8]) -> Option<Vec<u8>> {
+    match lz4_flex::block::uncompressed_size(payload) {
+        Ok((len, _)) if len <= MAX_CHUNK => lz4_flex::decompress_size_prepended(payload).ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,400 @@
+//! Runtime configuration, read once when the library is loaded.
+//!
+//! Settings come from a `key = value` file and from `NETREDIRECT_<KEY>`
+//! environment variables, the latter taking precedence. The file is the one
+//! named by `NETREDIRECT_CONFIG`, or `netredirect.ini` in the working
+//! directory of the game. Anything missing or invalid keeps its default.
+
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::transport::TcpTransport;
+
+const DEFAULT_CONFIG_FILE: &str = "netredirect.ini";
+const ENV_PREFIX: &str = "NETREDIRECT_";
+
+pub const DEFAULT_KORE_HOST: &str = "127.0.0.1";
+pub const DEFAULT_KORE_PORT: u16 = 2350;
+pub const DEFAULT_KORE_PATH: &str = "xkore.sock";
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_millis(5000);
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_millis(10000);
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_millis(1000);
+pub const DEFAULT_RECONNECT_MAX_DELAY: Duration = Duration::from_millis(60000);
+pub const DEFAULT_RECONNECT_JITTER: f64 = 0.2;
+pub const DEFAULT_KORE_QUEUE_LIMIT: usize = 4 * 1024 * 1024;
+pub const DEFAULT_HELLO_TIMEOUT: Duration = Duration::from_millis(1000);
+pub const DEFAULT_OBSERVER_HOST: &str = "127.0.0.1";
+pub const DEFAULT_OBSERVER_QUEUE_LIMIT: usize = 1024 * 1024;
+
+/// Which address family to use when resolving the Kore host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Any,
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub(crate) fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::V4 => addr.is_ipv4(),
+            AddressFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// What kind of connection is made to Kore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// TCP to `kore_host`:`kore_port`.
+    Tcp,
+    /// A Unix domain socket at `kore_path`.
+    Unix,
+    /// Listen on `kore_host`:`kore_port` and wait for Kore to connect.
+    Listen,
+}
+
+/// A standby Kore to fail over to, given as `host:port`, `tcp://host:port`
+/// or `unix:path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    fn parse(text: &str) -> Option<Endpoint> {
+        if let Some(path) = text.strip_prefix("unix:") {
+            return (!path.is_empty()).then(|| Endpoint::Unix(PathBuf::from(path)));
+        }
+        let (host, port) = text.strip_prefix("tcp://").unwrap_or(text).rsplit_once(':')?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        Some(Endpoint::Tcp { host: host.to_string(), port: port.parse().ok()? })
+    }
+}
+
+/// When a standby Kore gives way to the primary again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailbackPolicy {
+    /// Whenever the link to a standby is lost, try the primary first.
+    Reconnect,
+    /// Stay with whichever endpoint works until it fails.
+    Never,
+    /// Drop a standby after it has been connected this long and try the
+    /// primary, falling back to the standby if the primary is still down.
+    After(Duration),
+}
+
+/// What to do with captured traffic when the queue for Kore is full.
+///
+/// Client data is never dropped; when it does not fit even after dropping
+/// frames from the server, Kore is given up on as with `Disconnect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Make the game thread wait until the relay has caught up.
+    Block,
+    /// Drop the oldest queued frames from the server to make room.
+    DropOldest,
+    /// Drop the frame from the server that did not fit.
+    DropNewest,
+    /// Give up on Kore and pass the queued client data through to the server.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub kore_transport: TransportKind,
+    pub kore_host: String,
+    pub kore_port: u16,
+    pub kore_family: AddressFamily,
+    pub kore_path: PathBuf,
+    /// Standby Kores, tried in order when the endpoint above fails.
+    pub kore_failover: Vec<Endpoint>,
+    pub kore_failback: FailbackPolicy,
+    /// Whether the link to Kore is wrapped in TLS.
+    pub kore_tls: bool,
+    /// The certificate Kore must present, PEM or DER. Only this exact
+    /// certificate is accepted.
+    pub kore_tls_cert: Option<PathBuf>,
+    /// Shared secret Kore has to prove it knows before it gets any traffic.
+    pub kore_secret: Option<String>,
+    /// Whether to offer Kore a hello frame on connect. Off by default, since
+    /// a Kore that predates the hello does not expect the frame.
+    pub kore_hello: bool,
+    /// How long to wait for Kore's hello before falling back to the legacy
+    /// protocol.
+    pub hello_timeout: Duration,
+    /// Whether to offer Kore timestamps and sequence numbers on captured
+    /// traffic. Features are offered in the hello, so this needs
+    /// `kore_hello`.
+    pub kore_timestamps: bool,
+    /// Whether to offer Kore LZ4 compression of the link. Needs
+    /// `kore_hello`, like `kore_timestamps`.
+    pub kore_compression: bool,
+    /// How often a `K` keep-alive is sent to Kore.
+    pub ping_interval: Duration,
+    /// How long Kore may stay silent before the link is declared dead. Also
+    /// bounds each attempt to connect to one of Kore's addresses.
+    pub ping_timeout: Duration,
+    /// Delay before the first reconnect attempt, doubled after each failure.
+    pub reconnect_delay: Duration,
+    /// Ceiling for the reconnect delay.
+    pub reconnect_max_delay: Duration,
+    /// Fraction of each reconnect delay that is randomly taken off, `0.0..=1.0`.
+    pub reconnect_jitter: f64,
+    /// How many bytes of encoded frames may wait to be written to Kore.
+    pub kore_queue_limit: usize,
+    pub kore_overflow: OverflowPolicy,
+    pub observer_host: String,
+    /// Where read-only observers connect; observers are off when unset.
+    pub observer_port: Option<u16>,
+    /// How many bytes of frames may wait for each observer before frames
+    /// are dropped for it.
+    pub observer_queue_limit: usize,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Syntax { line: usize },
+    UnknownKey(String),
+    InvalidValue { key: String, value: String },
+    /// A setting that does nothing unless another one is set too.
+    Unused { key: String, needs: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Syntax { line } => write!(f, "line {}: expected `key = value`", line),
+            ConfigError::UnknownKey(key) => write!(f, "unknown setting `{}`", key),
+            ConfigError::InvalidValue { key, value } => write!(f, "invalid value `{}` for `{}`", value, key),
+            ConfigError::Unused { key, needs } => write!(f, "`{}` has no effect unless `{}` is set", key, needs),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            kore_transport: TransportKind::Tcp,
+            kore_host: DEFAULT_KORE_HOST.to_string(),
+            kore_port: DEFAULT_KORE_PORT,
+            kore_family: AddressFamily::Any,
+            kore_path: PathBuf::from(DEFAULT_KORE_PATH),
+            kore_failover: Vec::new(),
+            kore_failback: FailbackPolicy::Reconnect,
+            kore_tls: false,
+            kore_tls_cert: None,
+            kore_secret: None,
+            kore_hello: false,
+            hello_timeout: DEFAULT_HELLO_TIMEOUT,
+            kore_timestamps: false,
+            kore_compression: false,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            reconnect_max_delay: DEFAULT_RECONNECT_MAX_DELAY,
+            reconnect_jitter: DEFAULT_RECONNECT_JITTER,
+            kore_queue_limit: DEFAULT_KORE_QUEUE_LIMIT,
+            kore_overflow: OverflowPolicy::Disconnect,
+            observer_host: DEFAULT_OBSERVER_HOST.to_string(),
+            observer_port: None,
+            observer_queue_limit: DEFAULT_OBSERVER_QUEUE_LIMIT,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the config file and the environment,
+    /// reporting and skipping any setting that cannot be applied.
+    pub fn load() -> Config {
+        let mut config = Config::default();
+
+        let (path, explicit) = match env::var_os(format!("{}CONFIG", ENV_PREFIX)) {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for e in config.apply_file(&text) {
+                    println!("Config {}: {}", path.display(), e);
+                }
+            }
+            Err(e) if explicit || e.kind() != io::ErrorKind::NotFound => {
+                println!("Config: {}", ConfigError::Io(path, e));
+            }
+            Err(_) => {}
+        }
+
+        for e in config.apply_env(env::vars_os()) {
+            println!("Config environment: {}", e);
+        }
+        for e in config.check() {
+            println!("Config: {}", e);
+        }
+        config
+    }
+
+    /// Finds settings that are valid on their own but do nothing with the
+    /// others as they are.
+    pub fn check(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if !self.kore_hello {
+            for (key, set) in [("kore_timestamps", self.kore_timestamps), ("kore_compression", self.kore_compression)] {
+                if set {
+                    errors.push(ConfigError::Unused { key: key.to_string(), needs: "kore_hello".to_string() });
+                }
+            }
+        }
+        errors
+    }
+
+    /// Applies every `key = value` line of a config file. Blank lines and
+    /// lines starting with `#` or `;` are ignored.
+    pub fn apply_file(&mut self, text: &str) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value.trim()),
+                None => Err(ConfigError::Syntax { line: i + 1 }),
+            };
+            errors.extend(result.err());
+        }
+        errors
+    }
+
+    /// Applies every `NETREDIRECT_<KEY>` variable, e.g. `NETREDIRECT_KORE_PORT`.
+    /// Other variables are skipped even if they are not valid Unicode.
+    pub fn apply_env<K, V>(&mut self, vars: impl IntoIterator<Item = (K, V)>) -> Vec<ConfigError>
+    where
+        K: Into<OsString>,
+        V: Into<OsString>,
+    {
+        let mut errors = Vec::new();
+        for (name, value) in vars {
+            let name = name.into();
+            let key = match name.to_str().and_then(|name| name.strip_prefix(ENV_PREFIX)) {
+                Some(key) if key != "CONFIG" => key.to_ascii_lowercase(),
+                _ => continue,
+            };
+            let result = match value.into().into_string() {
+                Ok(value) => self.set(&key, value.trim()),
+                Err(value) => Err(ConfigError::InvalidValue { key, value: value.to_string_lossy().into_owned() }),
+            };
+            errors.extend(result.err());
+        }
+        errors
+    }
+
+    /// Sets a single setting by name. Durations are given in milliseconds,
+    /// `reconnect_jitter` in percent and the queue limits in bytes.
+    /// `kore_failover` is a comma-separated list of endpoints.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue { key: key.to_string(), value: value.to_string() };
+        let millis = || match value.parse() {
+            Ok(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+            _ => Err(invalid()),
+        };
+        let flag = || match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(invalid()),
+        };
+        match key {
+            "kore_transport" => {
+                self.kore_transport = match value.to_ascii_lowercase().as_str() {
+                    "tcp" => TransportKind::Tcp,
+                    "unix" => TransportKind::Unix,
+                    "listen" => TransportKind::Listen,
+                    _ => return Err(invalid()),
+                }
+            }
+            "kore_host" if !value.is_empty() => self.kore_host = value.to_string(),
+            "kore_port" => self.kore_port = value.parse().map_err(|_| invalid())?,
+            "kore_family" => {
+                self.kore_family = match value.to_ascii_lowercase().as_str() {
+                    "any" => AddressFamily::Any,
+                    "ipv4" | "v4" | "4" => AddressFamily::V4,
+                    "ipv6" | "v6" | "6" => AddressFamily::V6,
+                    _ => return Err(invalid()),
+                }
+            }
+            "kore_path" if !value.is_empty() => self.kore_path = PathBuf::from(value),
+            "kore_failover" => {
+                self.kore_failover = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|endpoint| !endpoint.is_empty())
+                    .map(|endpoint| Endpoint::parse(endpoint).ok_or_else(invalid))
+                    .collect::<Result<_, _>>()?
+            }
+            "kore_failback" => {
+                self.kore_failback = match value.to_ascii_lowercase().as_str() {
+                    "reconnect" => FailbackPolicy::Reconnect,
+                    "never" => FailbackPolicy::Never,
+                    _ => FailbackPolicy::After(millis()?),
+                }
+            }
+            "kore_tls" => self.kore_tls = flag()?,
+            "kore_tls_cert" if !value.is_empty() => self.kore_tls_cert = Some(PathBuf::from(value)),
+            "kore_secret" if !value.is_empty() => self.kore_secret = Some(value.to_string()),
+            "kore_hello" => self.kore_hello = flag()?,
+            "hello_timeout" => self.hello_timeout = millis()?,
+            "kore_timestamps" => self.kore_timestamps = flag()?,
+            "kore_compression" => self.kore_compression = flag()?,
+            "ping_interval" => self.ping_interval = millis()?,
+            "ping_timeout" => self.ping_timeout = millis()?,
+            "reconnect_delay" => self.reconnect_delay = millis()?,
+            "reconnect_max_delay" => self.reconnect_max_delay = millis()?,
+            "reconnect_jitter" => match value.parse::<u8>() {
+                Ok(percent) if percent <= 100 => self.reconnect_jitter = percent as f64 / 100.0,
+                _ => return Err(invalid()),
+            },
+            "kore_queue_limit" => match value.parse() {
+                Ok(bytes) if bytes > 0 => self.kore_queue_limit = bytes,
+                _ => return Err(invalid()),
+            },
+            "kore_overflow" => {
+                self.kore_overflow = match value.to_ascii_lowercase().as_str() {
+                    "block" => OverflowPolicy::Block,
+                    "drop_oldest" => OverflowPolicy::DropOldest,
+                    "drop_newest" => OverflowPolicy::DropNewest,
+                    "disconnect" => OverflowPolicy::Disconnect,
+                    _ => return Err(invalid()),
+                }
+            }
+            "observer_host" if !value.is_empty() => self.observer_host = value.to_string(),
+            "observer_port" => self.observer_port = Some(value.parse().map_err(|_| invalid())?),
+            "observer_queue_limit" => match value.parse() {
+                Ok(bytes) if bytes > 0 => self.observer_queue_limit = bytes,
+                _ => return Err(invalid()),
+            },
+            "kore_host" | "kore_path" | "kore_tls_cert" | "kore_secret" | "observer_host" => return Err(invalid()),
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Resolves the Kore endpoint, keeping only addresses of the configured family.
+    pub fn kore_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        TcpTransport::new(&self.kore_host, self.kore_port, self).addrs()
+    }
+}
@@ -0,0 +1,91 @@
+//! Choosing which of several Kore endpoints to connect to.
+
+use std::time::Instant;
+
+use crate::config::FailbackPolicy;
+
+/// Tracks the active endpoint in an ordered list whose first entry is the
+/// primary and the rest are standbys.
+///
+/// A refused attempt or a lost link moves on to the next endpoint right
+/// away; only after every endpoint has failed in a row does the relay back
+/// off. The [`FailbackPolicy`] decides when a standby gives way to the
+/// primary again.
+#[derive(Debug, Clone)]
+pub struct Failover {
+    count: usize,
+    policy: FailbackPolicy,
+    current: usize,
+    /// Attempts that failed since the last connection.
+    failed: usize,
+    connected_at: Option<Instant>,
+}
+
+impl Failover {
+    /// Failover between `count` endpoints, which must be at least one.
+    pub fn new(count: usize, policy: FailbackPolicy) -> Self {
+        assert!(count > 0, "no endpoints to fail over between");
+        Failover { count, policy, current: 0, failed: 0, connected_at: None }
+    }
+
+    /// Index of the endpoint to connect to.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Records a working connection to the current endpoint.
+    pub fn connected(&mut self) {
+        self.failed = 0;
+        self.connected_at = Some(Instant::now());
+    }
+
+    /// Moves on after a failed attempt. Returns `true` once every endpoint
+    /// has failed in a row, when it is time to back off.
+    pub fn attempt_failed(&mut self) -> bool {
+        self.failed += 1;
+        self.current = (self.current + 1) % self.count;
+        if self.failed < self.count {
+            return false;
+        }
+        self.failed = 0;
+        if self.policy != FailbackPolicy::Never {
+            self.current = 0;
+        }
+        true
+    }
+
+    /// Picks the endpoint to try after the current one's link died. Returns
+    /// `false` if that is the same endpoint, which then deserves a backoff.
+    pub fn link_lost(&mut self) -> bool {
+        self.connected_at = None;
+        if self.count == 1 {
+            return false;
+        }
+        self.current = match self.policy {
+            FailbackPolicy::Reconnect if self.current != 0 => 0,
+            _ => (self.current + 1) % self.count,
+        };
+        true
+    }
+
+    /// Whether the link to a standby has lasted long enough to try the
+    /// primary again.
+    pub fn should_fail_back(&self) -> bool {
+        self.fail_back_at().is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// When the current link will have lasted long enough to try the primary
+    /// again, if it is to a standby and the policy fails back at all.
+    pub fn fail_back_at(&self) -> Option<Instant> {
+        match (self.policy, self.connected_at) {
+            (FailbackPolicy::After(delay), Some(since)) if self.current != 0 => Some(since + delay),
+            _ => None,
+        }
+    }
+
+    /// Makes the primary the next endpoint to connect to.
+    pub fn fail_back(&mut self) {
+        self.current = 0;
+        self.connected_at = None;
+    }
+}
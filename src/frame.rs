@@ -0,0 +1,237 @@
+//! The X-Kore wire format shared by the DLL and OpenKore.
+//!
+//! Every frame is a one byte type followed by a little-endian `u16` payload
+//! length and the payload itself.
+
+use crate::compress;
+
+pub const HEADER_LEN: usize = 3;
+/// The largest payload a single frame can carry.
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// A single frame on the X-Kore link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XKoreFrame {
+    /// `R`: server to client data. The DLL reports everything the client
+    /// receives this way, and OpenKore uses it to inject data into the client.
+    Received(Vec<u8>),
+    /// `S`: client to server data. The DLL reports everything the client
+    /// sends this way, and OpenKore uses it to write data to the RO server.
+    Sent(Vec<u8>),
+    /// `K`: keep-alive. Any payload is ignored.
+    KeepAlive,
+    /// `A`: authentication. The DLL sends a random challenge and Kore answers
+    /// with an HMAC of it, see [`handshake`](crate::handshake).
+    Auth(Vec<u8>),
+    /// `H`: hello, exchanged once on connect to agree on a protocol version
+    /// and optional features, see [`handshake::Hello`](crate::handshake::Hello).
+    Hello(Vec<u8>),
+    /// `T`: when and in what order the `R` or `S` frame right after it was
+    /// captured, see [`Stamp`]. Only sent when both sides agreed on it.
+    Timestamp(Vec<u8>),
+    /// `Z`: LZ4-compressed frames, see [`compress`](crate::compress).
+    /// [`FrameDecoder`] unpacks these itself.
+    Compressed(Vec<u8>),
+    /// A frame with a type byte we do not understand.
+    Unknown { kind: u8, payload: Vec<u8> },
+}
+
+impl XKoreFrame {
+    /// The type byte written in the frame header.
+    pub fn kind(&self) -> u8 {
+        match self {
+            XKoreFrame::Received(_) => b'R',
+            XKoreFrame::Sent(_) => b'S',
+            XKoreFrame::KeepAlive => b'K',
+            XKoreFrame::Auth(_) => b'A',
+            XKoreFrame::Hello(_) => b'H',
+            XKoreFrame::Timestamp(_) => b'T',
+            XKoreFrame::Compressed(_) => b'Z',
+            XKoreFrame::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            XKoreFrame::Received(data)
+            | XKoreFrame::Sent(data)
+            | XKoreFrame::Auth(data)
+            | XKoreFrame::Hello(data)
+            | XKoreFrame::Timestamp(data)
+            | XKoreFrame::Compressed(data) => data,
+            XKoreFrame::KeepAlive => &[],
+            XKoreFrame::Unknown { payload, .. } => payload,
+        }
+    }
+
+    /// Number of bytes the frame takes up on the wire.
+    pub fn encoded_len(&self) -> usize {
+        let len = self.payload().len();
+        HEADER_LEN * len.div_ceil(MAX_PAYLOAD).max(1) + len
+    }
+
+    /// Appends the encoded frame to `out`.
+    ///
+    /// A payload longer than [`MAX_PAYLOAD`] is split over several frames of
+    /// the same type. `R` and `S` payloads are parts of a TCP stream, so Kore
+    /// sees the same bytes either way.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        let payload = self.payload();
+        out.reserve(self.encoded_len());
+        if payload.is_empty() {
+            out.extend_from_slice(&[self.kind(), 0, 0]);
+        }
+        for chunk in payload.chunks(MAX_PAYLOAD) {
+            out.push(self.kind());
+            out.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            out.extend_from_slice(chunk);
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    /// Decodes the frame at the start of `data`.
+    ///
+    /// Returns the frame and the number of bytes it occupied, or `None` if
+    /// `data` does not yet hold a complete frame.
+    pub fn decode(data: &[u8]) -> Option<(XKoreFrame, usize)> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+
+        let kind = data[0];
+        let len = u16::from_le_bytes([data[1], data[2]]) as usize;
+        let end = HEADER_LEN + len;
+        if data.len() < end {
+            return None;
+        }
+
+        let payload = data[HEADER_LEN..end].to_vec();
+        let frame = match kind {
+            b'R' => XKoreFrame::Received(payload),
+            b'S' => XKoreFrame::Sent(payload),
+            b'K' => XKoreFrame::KeepAlive,
+            b'A' => XKoreFrame::Auth(payload),
+            b'H' => XKoreFrame::Hello(payload),
+            b'T' => XKoreFrame::Timestamp(payload),
+            b'Z' => XKoreFrame::Compressed(payload),
+            _ => XKoreFrame::Unknown { kind, payload },
+        };
+        Some((frame, end))
+    }
+}
+
+/// The payload of a `T` frame: `[u8 direction][u32 sequence][u64 micros]`,
+/// little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    /// The type of the frame being stamped, `R` or `S`.
+    pub direction: u8,
+    /// Counts the frames of one direction, including any the overflow policy
+    /// dropped, so gaps show where traffic went missing.
+    pub sequence: u32,
+    /// Microseconds since the library was loaded, from a monotonic clock.
+    pub micros: u64,
+}
+
+impl Stamp {
+    pub const LEN: usize = 13;
+
+    pub fn to_frame(self) -> XKoreFrame {
+        let mut payload = Vec::with_capacity(Stamp::LEN);
+        payload.push(self.direction);
+        payload.extend_from_slice(&self.sequence.to_le_bytes());
+        payload.extend_from_slice(&self.micros.to_le_bytes());
+        XKoreFrame::Timestamp(payload)
+    }
+
+    pub fn parse(payload: &[u8]) -> Option<Stamp> {
+        if payload.len() < Stamp::LEN {
+            return None;
+        }
+        Some(Stamp {
+            direction: payload[0],
+            sequence: u32::from_le_bytes(payload[1..5].try_into().unwrap()),
+            micros: u64::from_le_bytes(payload[5..13].try_into().unwrap()),
+        })
+    }
+}
+
+/// Incremental decoder for the X-Kore stream.
+///
+/// A single read may return part of a frame or several frames at once, so
+/// bytes are buffered here until the declared length has fully arrived.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// Whether `Z` frames are unpacked, i.e. compression was agreed.
+    compressed: bool,
+    /// How many bytes at the start of `buf` came out of a `Z` frame.
+    unpacked: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder::default()
+    }
+
+    /// Unpacks `Z` frames from now on, once both sides agreed on
+    /// [`Features::COMPRESSION`](crate::handshake::Features::COMPRESSION).
+    pub fn accept_compressed(&mut self, accept: bool) {
+        self.compressed = accept;
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.unpacked = 0;
+    }
+
+    /// Number of buffered bytes that have not been returned as a frame yet.
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The type of the next frame if it has fully arrived.
+    pub fn peek_kind(&self) -> Option<u8> {
+        if self.buf.len() < HEADER_LEN {
+            return None;
+        }
+        let len = u16::from_le_bytes([self.buf[1], self.buf[2]]) as usize;
+        (self.buf.len() >= HEADER_LEN + len).then_some(self.buf[0])
+    }
+
+    /// Returns the next complete frame, or `None` if more data is needed.
+    ///
+    /// `Z` frames are decompressed back into the stream and never returned.
+    /// One that cannot be decompressed, was itself compressed or arrives
+    /// before compression was accepted comes back as
+    /// [`XKoreFrame::Unknown`]; unpacking one never yields more than
+    /// [`MAX_CHUNK`](compress::MAX_CHUNK) bytes.
+    pub fn next_frame(&mut self) -> Option<XKoreFrame> {
+        loop {
+            let (frame, used) = XKoreFrame::decode(&self.buf)?;
+            let nested = self.unpacked > 0;
+            self.unpacked = self.unpacked.saturating_sub(used);
+            if let XKoreFrame::Compressed(payload) = frame {
+                let data = if self.compressed && !nested { compress::decompress(&payload) } else { None };
+                if let Some(data) = data {
+                    self.unpacked = data.len();
+                    self.buf.splice(..used, data);
+                    continue;
+                }
+                self.buf.drain(..used);
+                return Some(XKoreFrame::Unknown { kind: b'Z', payload });
+            }
+            self.buf.drain(..used);
+            return Some(frame);
+        }
+    }
+}
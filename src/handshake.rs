@@ -0,0 +1,179 @@
+//! What happens on a new Kore connection before any game traffic flows.
+//!
+//! With `kore_secret` set, the DLL sends an `A` frame carrying a random
+//! challenge and Kore has to answer with an `A` frame carrying
+//! HMAC-SHA256(secret, challenge). A peer that answers wrongly or not at all
+//! is disconnected without ever seeing an `R` or `S` frame.
+//!
+//! The DLL then offers a [`Hello`]. Kore answers with its own and both sides
+//! use the lower version and the features they have in common. A Kore that
+//! does not answer, or sends anything else first, gets the legacy protocol.
+
+use std::io;
+use std::ops::BitOr;
+use std::time::{Duration, Instant};
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::frame::{FrameDecoder, XKoreFrame};
+use crate::transport::KoreStream;
+
+pub const CHALLENGE_LEN: usize = 32;
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Optional protocol features, sent as bit flags in the hello.
+///
+/// Bits 0 and 3 are reserved for frames longer than 65535 bytes and for
+/// several game connections over one link, which nothing implements yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(pub u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// Timestamps and sequence numbers on captured traffic.
+    pub const TIMESTAMPS: Features = Features(1 << 1);
+    /// Compressed frames.
+    pub const COMPRESSION: Features = Features(1 << 2);
+
+    /// The features this build implements and may offer.
+    pub const SUPPORTED: Features = Features(Features::TIMESTAMPS.0 | Features::COMPRESSION.0);
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersect(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+/// The payload of an `H` frame: `[u16 version][u32 features]`, little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub features: Features,
+}
+
+impl Hello {
+    pub fn to_frame(self) -> XKoreFrame {
+        let mut payload = self.version.to_le_bytes().to_vec();
+        payload.extend_from_slice(&self.features.0.to_le_bytes());
+        XKoreFrame::Hello(payload)
+    }
+
+    /// Reads a hello payload. Anything after the features is ignored, so
+    /// later versions can add fields.
+    pub fn parse(payload: &[u8]) -> Option<Hello> {
+        if payload.len() < 6 {
+            return None;
+        }
+        Some(Hello {
+            version: u16::from_le_bytes([payload[0], payload[1]]),
+            features: Features(u32::from_le_bytes([payload[2], payload[3], payload[4], payload[5]])),
+        })
+    }
+}
+
+/// The answer Kore must give to `challenge`.
+pub fn respond(secret: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::sign(&key, challenge).as_ref().to_vec()
+}
+
+/// Challenges Kore and checks its answer, waiting at most `timeout` for it.
+///
+/// Frames Kore sends after its answer are left in `decoder`.
+pub fn authenticate(
+    client: &mut dyn KoreStream,
+    decoder: &mut FrameDecoder,
+    secret: &[u8],
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    SystemRandom::new()
+        .fill(&mut challenge)
+        .map_err(|_| io::Error::other("no random numbers for the challenge"))?;
+    client.write_all(&XKoreFrame::Auth(challenge.to_vec()).encode())?;
+
+    if !wait_for_frame(client, decoder, timeout)? {
+        return Err(denied("no answer to the challenge"));
+    }
+    let answer = match decoder.next_frame() {
+        Some(XKoreFrame::Auth(answer)) => answer,
+        Some(other) => return Err(denied(&format!("expected an answer, got a {:?} frame", other.kind() as char))),
+        None => unreachable!("wait_for_frame returned without a frame"),
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::verify(&key, &challenge, &answer).map_err(|_| denied("wrong answer to the challenge"))
+}
+
+/// Offers `features` to Kore and returns what was agreed, or `None` if Kore
+/// did not answer within `timeout` and only speaks the legacy protocol.
+///
+/// A frame Kore sends instead of a hello is left in `decoder`.
+pub fn negotiate(
+    client: &mut dyn KoreStream,
+    decoder: &mut FrameDecoder,
+    features: Features,
+    timeout: Duration,
+) -> io::Result<Option<Hello>> {
+    let offer = Hello { version: PROTOCOL_VERSION, features };
+    client.write_all(&offer.to_frame().encode())?;
+
+    if !wait_for_frame(client, decoder, timeout)? || decoder.peek_kind() != Some(b'H') {
+        return Ok(None);
+    }
+    let answer = match decoder.next_frame() {
+        Some(XKoreFrame::Hello(payload)) => Hello::parse(&payload),
+        _ => None,
+    };
+    Ok(answer.map(|answer| Hello {
+        version: offer.version.min(answer.version),
+        features: offer.features.intersect(answer.features),
+    }))
+}
+
+/// Reads until `decoder` holds a complete frame other than a keep-alive.
+/// Returns `false` if none arrived within `timeout`, however the peer
+/// spreads out what it sends.
+fn wait_for_frame(client: &mut dyn KoreStream, decoder: &mut FrameDecoder, timeout: Duration) -> io::Result<bool> {
+    let mut buf = [0u8; 256];
+    let deadline = Instant::now() + timeout;
+    let arrived = loop {
+        match decoder.peek_kind() {
+            Some(b'K') => {
+                decoder.next_frame();
+                continue;
+            }
+            Some(_) => break true,
+            None => {}
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break false;
+        }
+        client.set_read_timeout(Some(remaining))?;
+        match client.read(&mut buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => decoder.extend(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break false,
+            Err(e) => return Err(e),
+        }
+    };
+    client.set_read_timeout(None)?;
+    Ok(arrived)
+}
+
+fn denied(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("authentication failed: {}", reason))
+}
@@ -0,0 +1,30 @@
+//! The interface between the portable relay and a platform's socket hooks.
+
+use std::io;
+
+use crate::state::RawSocket;
+
+/// Access to the original, unhooked socket functions of the platform.
+///
+/// Each backend installs its hooks, feeds captured traffic into the shared
+/// [`NetworkState`](crate::state::NetworkState) and hands the relay an
+/// implementation of this trait so Kore-originated data can reach the server.
+pub trait SocketHooks: Send + Sync {
+    /// Sends `data` on `socket` bypassing the hooks, returning how many bytes
+    /// were written.
+    fn send(&self, socket: RawSocket, data: &[u8]) -> io::Result<usize>;
+
+    /// Called on every thread the relay starts, before it does anything else.
+    fn on_relay_thread(&self) {}
+
+    /// Sends all of `data` on `socket`.
+    fn send_all(&self, socket: RawSocket, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.send(socket, data)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => data = &data[n..],
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+//! Read-only observers: packet loggers, dashboards and the like that get a
+//! copy of the traffic captured from the game.
+//!
+//! Observers connect to `observer_host`:`observer_port` and receive plain
+//! `R` and `S` frames, whether or not Kore is connected. Each one has its own
+//! bounded queue and writer thread; when an observer falls behind only its
+//! own queue overflows, so neither Kore nor the game ever waits for it.
+//! Observers cannot inject traffic: `R` and `S` frames they send are
+//! rejected. When `kore_secret` is set, observers have to answer the same
+//! challenge as Kore before they get any traffic.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::frame::{FrameDecoder, XKoreFrame};
+use crate::handshake;
+use crate::relay::RelayThreads;
+use crate::state::{NetworkState, QueueDrops};
+use crate::transport::{Acceptor, TcpTransport};
+
+const BUF_SIZE: usize = 4096;
+/// How long to wait before accepting observers again after it failed.
+const ACCEPT_RETRY: Duration = Duration::from_secs(1);
+
+/// One connected observer, shared by the game threads that queue frames for
+/// it and its writer and reader threads.
+pub struct Observer {
+    peer: SocketAddr,
+    /// A handle on the connection for hanging up on the observer.
+    socket: TcpStream,
+    limit: usize,
+    queue: Mutex<ObserverQueue>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct ObserverQueue {
+    frames: VecDeque<XKoreFrame>,
+    /// Encoded size of everything in `frames`.
+    queued: usize,
+    dropped: QueueDrops,
+    closed: bool,
+}
+
+impl Observer {
+    /// Queues a copy of `frame`, dropping it if the observer's queue is full.
+    /// Returns `false` once the observer has gone.
+    pub(crate) fn push(&self, frame: &XKoreFrame) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return false;
+        }
+        let len = frame.encoded_len();
+        if queue.queued + len > self.limit {
+            if queue.dropped.frames == 0 {
+                println!("Observer {} is falling behind, dropping frames", self.peer);
+            }
+            queue.dropped.frames += 1;
+            queue.dropped.bytes += frame.payload().len() as u64;
+            return true;
+        }
+        queue.queued += len;
+        queue.frames.push_back(frame.clone());
+        self.ready.notify_one();
+        true
+    }
+
+    /// Stops queueing for the observer and lets its writer finish.
+    pub(crate) fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Hangs up on the observer, which also wakes a writer stuck on an
+    /// observer that stopped reading.
+    pub(crate) fn disconnect(&self) {
+        self.close();
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    /// Waits for queued frames, returning `None` once the observer is closed
+    /// and everything queued has been handed out.
+    fn next_frames(&self) -> Option<VecDeque<XKoreFrame>> {
+        let mut queue = self.ready.wait_while(self.queue.lock().unwrap(), |q| q.frames.is_empty() && !q.closed).unwrap();
+        if queue.frames.is_empty() {
+            return None;
+        }
+        queue.queued = 0;
+        Some(std::mem::take(&mut queue.frames))
+    }
+
+    /// What was dropped for this observer because it fell behind.
+    pub fn dropped(&self) -> QueueDrops {
+        self.queue.lock().unwrap().dropped
+    }
+}
+
+/// Accepts observers on a relay thread of its own.
+pub struct ObserverListener {
+    acceptor: Acceptor,
+    admission: Arc<Admission>,
+}
+
+/// What the threads serving accepted observers need to let them in.
+struct Admission {
+    limit: usize,
+    secret: Option<String>,
+    /// How long an observer may take to answer the challenge.
+    timeout: Duration,
+    /// Observers that have not been let in yet, hung up on when the relay
+    /// stops. `None` once it has.
+    pending: Mutex<Option<Vec<(SocketAddr, TcpStream)>>>,
+}
+
+impl ObserverListener {
+    /// Binds the observer address and starts accepting observers, or returns
+    /// `None` if observers are not enabled or the address cannot be bound.
+    pub(crate) fn bind(config: &Config, threads: &RelayThreads) -> Option<ObserverListener> {
+        let port = config.observer_port?;
+        let addr = TcpTransport::new(&config.observer_host, port, config);
+        let listener = match addr.addrs().and_then(|addrs| TcpListener::bind(&addrs[..])) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Cannot accept observers on {}:{}: {}", config.observer_host, port, e);
+                return None;
+            }
+        };
+        if let Ok(local) = listener.local_addr() {
+            println!("Accepting observers on {}", local);
+        }
+
+        let admission = Arc::new(Admission {
+            limit: config.observer_queue_limit,
+            secret: config.kore_secret.clone(),
+            timeout: config.ping_timeout,
+            pending: Mutex::new(Some(Vec::new())),
+        });
+        let acceptor = Acceptor::new(listener);
+        let (accepting, admitting, serving) = (acceptor.clone(), admission.clone(), threads.clone());
+        threads.spawn(move || accept_main(&accepting, &admitting, &serving));
+        Some(ObserverListener { acceptor, admission })
+    }
+
+    /// Stops accepting observers and hangs up on those that have not been
+    /// let in yet; the relay disconnects the others itself.
+    pub(crate) fn close(&self) {
+        self.acceptor.close();
+        for (_, socket) in self.admission.pending.lock().unwrap().take().into_iter().flatten() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Starts serving every observer that connects until the listener is closed.
+fn accept_main(acceptor: &Acceptor, admission: &Arc<Admission>, threads: &RelayThreads) {
+    loop {
+        match acceptor.accept() {
+            Ok(Some((client, peer))) => {
+                if let Err(e) = admission.start(client, peer, threads) {
+                    println!("Cannot serve observer {}: {}", peer, e);
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                // Running out of sockets, say, passes; try again later.
+                println!("Accepting observers failed: {}", e);
+                thread::sleep(ACCEPT_RETRY);
+            }
+        }
+    }
+}
+
+impl Admission {
+    /// Starts serving the observer on `peer` on a thread of its own, unless
+    /// the relay has stopped since it connected.
+    fn start(self: &Arc<Self>, client: TcpStream, peer: SocketAddr, threads: &RelayThreads) -> io::Result<()> {
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.push((peer, client.try_clone()?)),
+            None => return Ok(()),
+        }
+        let (admission, for_observer) = (self.clone(), threads.clone());
+        threads.spawn(move || observer_main(client, peer, &admission, &for_observer));
+        Ok(())
+    }
+
+    /// Adds the observer on `peer` to `state`, unless the relay has stopped
+    /// since it connected.
+    fn admit(&self, peer: SocketAddr, state: &Mutex<NetworkState>) -> Option<Arc<Observer>> {
+        // Holding `pending` keeps the relay from disconnecting observers
+        // until this one is in the list.
+        let mut pending = self.pending.lock().unwrap();
+        let pending = pending.as_mut()?;
+        let index = pending.iter().position(|(addr, _)| *addr == peer)?;
+        let (_, socket) = pending.swap_remove(index);
+        let observer = Arc::new(Observer {
+            peer,
+            socket,
+            limit: self.limit,
+            queue: Mutex::new(ObserverQueue::default()),
+            ready: Condvar::new(),
+        });
+        state.lock().unwrap().observers.push(observer.clone());
+        Some(observer)
+    }
+
+    fn forget(&self, peer: SocketAddr) {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.retain(|(addr, _)| *addr != peer);
+        }
+    }
+}
+
+/// Checks the observer knows the secret, if one is set, then lets it in and
+/// serves it until it goes away.
+fn observer_main(mut client: TcpStream, peer: SocketAddr, admission: &Admission, threads: &RelayThreads) {
+    let mut decoder = FrameDecoder::new();
+    let checked = match &admission.secret {
+        Some(secret) => handshake::authenticate(&mut client, &mut decoder, secret.as_bytes(), admission.timeout),
+        None => Ok(()),
+    };
+    let writer = checked.and_then(|()| client.try_clone());
+    let writer = match writer {
+        Ok(writer) => writer,
+        Err(e) => {
+            println!("Observer {} refused: {}", peer, e);
+            admission.forget(peer);
+            let _ = client.shutdown(Shutdown::Both);
+            return;
+        }
+    };
+    let Some(observer) = admission.admit(peer, &threads.state) else {
+        return;
+    };
+    println!("Observer {} connected", peer);
+
+    let for_writer = observer.clone();
+    threads.spawn(move || observer_writer_main(writer, &for_writer));
+    observer_reader_main(client, decoder, &observer);
+}
+
+/// Writes queued frames to the observer until it is closed or goes away.
+fn observer_writer_main(mut client: TcpStream, observer: &Observer) {
+    while let Some(frames) = observer.next_frames() {
+        let mut data = Vec::with_capacity(frames.iter().map(XKoreFrame::encoded_len).sum());
+        for frame in frames {
+            frame.encode_into(&mut data);
+        }
+        if let Err(e) = client.write_all(&data) {
+            println!("Observer {}: write failed: {}", observer.peer, e);
+            break;
+        }
+    }
+    observer.close();
+    let _ = client.shutdown(Shutdown::Both);
+}
+
+/// Reads what the observer sends, which is only ever rejected, until it
+/// disconnects.
+fn observer_reader_main(mut client: TcpStream, mut decoder: FrameDecoder, observer: &Observer) {
+    let mut buf = [0u8; BUF_SIZE];
+    loop {
+        match client.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => decoder.extend(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+        while let Some(frame) = decoder.next_frame() {
+            if matches!(frame, XKoreFrame::Received(_) | XKoreFrame::Sent(_)) {
+                println!("Rejecting {} frame from observer {}", frame.kind() as char, observer.peer);
+            }
+        }
+    }
+    println!("Observer {} disconnected", observer.peer);
+    observer.close();
+}
@@ -0,0 +1,258 @@
+//! Linux backend: interposes libc `recv`/`send`/`read`/`write` when the
+//! library is loaded with `LD_PRELOAD`.
+//!
+//! Build with `cargo build --release --features preload` and start the game
+//! (or a test client, such as `examples/preload_client.rs`) with
+//! `LD_PRELOAD=target/release/libnetredirect_rust.so`.
+//! Only TCP sockets are redirected; every other file descriptor goes straight
+//! to libc.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::Duration;
+use libc::{c_int, c_void, size_t, ssize_t};
+
+use crate::config::Config;
+use crate::hooks::SocketHooks;
+use crate::relay::{self, RelayHandle};
+use crate::state::{
+    capture_recv, route_send, take_client_data, wait_for_kore_space, LinkState, NetworkState, RawSocket, SendRoute,
+    NETWORK_STATE,
+};
+
+type RecvFn = unsafe extern "C" fn(c_int, *mut c_void, size_t, c_int) -> ssize_t;
+type SendFn = unsafe extern "C" fn(c_int, *const c_void, size_t, c_int) -> ssize_t;
+type ReadFn = unsafe extern "C" fn(c_int, *mut c_void, size_t) -> ssize_t;
+type WriteFn = unsafe extern "C" fn(c_int, *const c_void, size_t) -> ssize_t;
+
+// Original libc functions
+struct Originals {
+    recv: RecvFn,
+    send: SendFn,
+    read: ReadFn,
+    write: WriteFn,
+}
+
+static ORIGINALS: OnceLock<Originals> = OnceLock::new();
+static RELAY: Mutex<Option<RelayHandle>> = Mutex::new(None);
+
+/// How long process exit waits for the relay to flush and disconnect.
+const SHUTDOWN_TIMEOUT: u64 = 1000;
+
+thread_local! {
+    /// Set on the relay thread and while a hook runs, so our own socket calls
+    /// (and anything the hooks call) reach libc untouched.
+    static BYPASS: Cell<bool> = const { Cell::new(false) };
+
+    /// The state, held by the forking thread across `fork()` so the child
+    /// does not inherit it locked by a thread it does not have.
+    static FORK_GUARD: RefCell<Option<MutexGuard<'static, NetworkState>>> = const { RefCell::new(None) };
+}
+
+unsafe fn lookup<T>(name: &CStr) -> T {
+    let sym = libc::dlsym(libc::RTLD_NEXT, name.as_ptr());
+    assert!(!sym.is_null(), "netredirect: {:?} not found", name);
+    mem::transmute_copy(&sym)
+}
+
+fn originals() -> &'static Originals {
+    ORIGINALS.get_or_init(|| unsafe {
+        Originals {
+            recv: lookup(c"recv"),
+            send: lookup(c"send"),
+            read: lookup(c"read"),
+            write: lookup(c"write"),
+        }
+    })
+}
+
+/// Marks the current thread as inside a hook until dropped.
+struct HookGuard;
+
+impl HookGuard {
+    /// Returns `None` if the calling thread must not be redirected.
+    fn enter() -> Option<HookGuard> {
+        // Not `then_some`: dropping an unused guard would clear the flag.
+        match BYPASS.try_with(|bypass| bypass.replace(true)) {
+            Ok(false) => Some(HookGuard),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for HookGuard {
+    fn drop(&mut self) {
+        let _ = BYPASS.try_with(|bypass| bypass.set(false));
+    }
+}
+
+fn is_tcp_socket(fd: c_int) -> bool {
+    let sockopt = |name| {
+        let mut value: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, name, &mut value as *mut c_int as *mut c_void, &mut len)
+        };
+        (ret == 0).then_some(value)
+    };
+
+    sockopt(libc::SO_TYPE) == Some(libc::SOCK_STREAM)
+        && matches!(sockopt(libc::SO_DOMAIN), Some(libc::AF_INET) | Some(libc::AF_INET6))
+}
+
+/// Sends through the original libc `send`.
+struct LibcHooks;
+
+impl SocketHooks for LibcHooks {
+    fn on_relay_thread(&self) {
+        BYPASS.with(|bypass| bypass.set(true));
+    }
+
+    fn send(&self, socket: RawSocket, data: &[u8]) -> io::Result<usize> {
+        let ret = unsafe {
+            (originals().send)(socket as c_int, data.as_ptr() as *const c_void, data.len(), libc::MSG_NOSIGNAL)
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+unsafe fn redirect_recv(fd: c_int, buffer: *mut c_void, len: size_t, flags: c_int, orig: impl FnOnce() -> ssize_t) -> ssize_t {
+    // Data injected by OpenKore takes priority over reading from the server,
+    // but only on the game's connection to it.
+    if len > 0 {
+        let mut state = NETWORK_STATE.lock().unwrap();
+        if state.ro_server() == Some(fd as RawSocket) && !state.client_data().is_empty() {
+            let out = std::slice::from_raw_parts_mut(buffer as *mut u8, len);
+            return take_client_data(&mut state, out, flags & libc::MSG_PEEK != 0) as ssize_t;
+        }
+    }
+
+    let ret = orig();
+    if ret > 0 {
+        let mut state = wait_for_kore_space(NETWORK_STATE.lock().unwrap());
+        let data = std::slice::from_raw_parts(buffer as *const u8, ret as usize);
+        capture_recv(&mut state, fd as RawSocket, data);
+    }
+    ret
+}
+
+unsafe fn redirect_send(fd: c_int, buffer: *const c_void, len: size_t, orig: impl FnOnce() -> ssize_t) -> ssize_t {
+    if len > 0 {
+        let mut state = wait_for_kore_space(NETWORK_STATE.lock().unwrap());
+        let data = std::slice::from_raw_parts(buffer as *const u8, len);
+        if route_send(&mut state, fd as RawSocket, data) != SendRoute::Direct {
+            return len as ssize_t;
+        }
+    }
+
+    // Send directly to RO server
+    orig()
+}
+
+// Hook implementations
+#[no_mangle]
+pub unsafe extern "C" fn recv(fd: c_int, buffer: *mut c_void, len: size_t, flags: c_int) -> ssize_t {
+    let orig = || (originals().recv)(fd, buffer, len, flags);
+    match HookGuard::enter() {
+        Some(_guard) if is_tcp_socket(fd) => redirect_recv(fd, buffer, len, flags, orig),
+        _ => orig(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn send(fd: c_int, buffer: *const c_void, len: size_t, flags: c_int) -> ssize_t {
+    let orig = || (originals().send)(fd, buffer, len, flags);
+    match HookGuard::enter() {
+        Some(_guard) if is_tcp_socket(fd) => redirect_send(fd, buffer, len, orig),
+        _ => orig(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn read(fd: c_int, buffer: *mut c_void, len: size_t) -> ssize_t {
+    let orig = || (originals().read)(fd, buffer, len);
+    match HookGuard::enter() {
+        Some(_guard) if is_tcp_socket(fd) => redirect_recv(fd, buffer, len, 0, orig),
+        _ => orig(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn write(fd: c_int, buffer: *const c_void, len: size_t) -> ssize_t {
+    let orig = || (originals().write)(fd, buffer, len);
+    match HookGuard::enter() {
+        Some(_guard) if is_tcp_socket(fd) => redirect_send(fd, buffer, len, orig),
+        _ => orig(),
+    }
+}
+
+/// Lets a controlling tool skip the reconnect backoff, e.g. after restarting
+/// OpenKore.
+#[no_mangle]
+pub extern "C" fn netredirect_reconnect() {
+    if let Some(handle) = RELAY.lock().unwrap().as_ref() {
+        handle.reconnect_now();
+    }
+}
+
+// Start the relay as soon as the library is loaded and stop it on exit
+#[used]
+#[link_section = ".init_array"]
+static INIT: extern "C" fn() = init;
+
+#[used]
+#[link_section = ".fini_array"]
+static FINI: extern "C" fn() = fini;
+
+extern "C" fn init() {
+    originals();
+    unsafe {
+        libc::pthread_atfork(Some(before_fork), Some(after_fork_in_parent), Some(after_fork_in_child));
+    }
+
+    let handle = relay::spawn(Config::load(), NETWORK_STATE.clone(), Arc::new(LibcHooks));
+    *RELAY.lock().unwrap() = Some(handle);
+}
+
+extern "C" fn fini() {
+    let handle = RELAY.lock().ok().and_then(|mut relay| relay.take());
+    if let Some(handle) = handle {
+        if !handle.shutdown(Duration::from_millis(SHUTDOWN_TIMEOUT)) {
+            println!("X-Kore relay did not stop in time");
+        }
+    }
+}
+
+extern "C" fn before_fork() {
+    let state = NETWORK_STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    FORK_GUARD.with(|guard| *guard.borrow_mut() = Some(state));
+}
+
+extern "C" fn after_fork_in_parent() {
+    FORK_GUARD.with(|guard| guard.borrow_mut().take());
+}
+
+/// A child forked without exec has no relay thread, so its traffic passes
+/// straight through. What was queued belongs to the parent and is left to it.
+extern "C" fn after_fork_in_child() {
+    if let Some(mut state) = FORK_GUARD.with(|guard| guard.borrow_mut().take()) {
+        // Not `set_link`: printing could wait on a lock held by a thread
+        // the child does not have.
+        state.link = LinkState::Disconnected;
+        state.take_kore_frames();
+        state.server_buf.clear();
+        state.send_buf.clear();
+        state.observers.clear();
+    }
+    // The handle refers to the parent's relay thread and Kore connection.
+    if let Ok(mut relay) = RELAY.try_lock() {
+        mem::forget(relay.take());
+    }
+}
@@ -0,0 +1,602 @@
+//! The connection to OpenKore: connecting, pinging and moving frames between
+//! the Kore socket and the shared [`NetworkState`].
+//!
+//! Socket I/O with Kore never happens while the state lock is held, so the
+//! game's hooked `recv`/`send` only ever wait for in-memory bookkeeping. The
+//! relay thread writes to Kore and a reader thread per connection blocks on
+//! reads and applies incoming frames.
+//!
+//! The relay thread sleeps until the state signals frames for Kore or a
+//! change of the link, or until a ping, a timeout or a reconnect is due, so
+//! captured traffic goes out as soon as it is queued.
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backoff::Backoff;
+use crate::compress;
+use crate::config::Config;
+use crate::failover::Failover;
+use crate::frame::{FrameDecoder, XKoreFrame};
+use crate::handshake::{self, Features, Hello};
+use crate::hooks::SocketHooks;
+use crate::observer::ObserverListener;
+use crate::state::{queue_client_data, LinkState, NetworkState};
+use crate::transport::{self, KoreStream, KoreTransport};
+
+const BUF_SIZE: usize = 4096;
+/// How long the relay may take to flush what is queued for Kore when it
+/// stops.
+pub(crate) const FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The writing end of the current Kore connection.
+type KoreSlot = Arc<Mutex<Option<Box<dyn KoreStream>>>>;
+
+/// Threads serving Kore and observer connections, joined on shutdown.
+type Workers = Arc<Mutex<Vec<thread::JoinHandle<()>>>>;
+
+/// A relay thread started with [`spawn`].
+pub struct RelayHandle {
+    keep_running: Arc<AtomicBool>,
+    reconnect: Arc<AtomicBool>,
+    done: mpsc::Receiver<()>,
+    thread: thread::JoinHandle<()>,
+    state: Arc<Mutex<NetworkState>>,
+    kore: KoreSlot,
+    workers: Workers,
+}
+
+impl RelayHandle {
+    /// Reconnects to Kore right away instead of waiting out the backoff,
+    /// dropping the current connection if there is one.
+    pub fn reconnect_now(&self) {
+        self.reconnect.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Asks the relay to stop, flush what is queued for Kore and close the
+    /// connection, waiting at most `timeout` for it to finish.
+    ///
+    /// Returns `false` if the relay did not finish in time.
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.keep_running.store(false, Ordering::SeqCst);
+        self.wake();
+        let finished = self.done.recv_timeout(timeout).is_ok();
+
+        // The loader lock is held during DLL_PROCESS_DETACH and an exiting
+        // thread needs it, so threads are never joined on Windows; the DLL
+        // pins itself instead so their code stays mapped.
+        if !finished || cfg!(windows) {
+            return finished;
+        }
+        let _ = self.thread.join();
+
+        // The relay closed every connection on its way out, so the threads
+        // serving them are about to finish. Some may start another on the
+        // way, hence the loop.
+        loop {
+            let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+            if workers.is_empty() {
+                return true;
+            }
+            for worker in workers {
+                while !worker.is_finished() {
+                    if Instant::now() >= deadline {
+                        return false;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+                let _ = worker.join();
+            }
+        }
+    }
+
+    /// Wakes the relay thread so it sees the flags.
+    fn wake(&self) {
+        // With the lock held the relay is either already waiting or has yet
+        // to check the flags, so the signal cannot get lost.
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.relay_wake.notify_one();
+    }
+
+    /// Flushes and closes the Kore connection from the calling thread.
+    ///
+    /// Meant for when the relay thread can no longer do it itself, such as
+    /// during process exit on Windows where it has already been terminated.
+    /// Gives up if the relay died while holding the state or the connection.
+    pub fn flush_now(self, timeout: Duration) -> bool {
+        let data = match self.state.try_lock() {
+            Ok(mut state) => {
+                state.set_link(LinkState::Disconnected, "process exit");
+                encode_frames(state.take_kore_frames())
+            }
+            Err(_) => return false,
+        };
+        match self.kore.try_lock() {
+            Ok(mut kore) => match kore.take() {
+                Some(client) => close_kore(client, &data, timeout),
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// Starts the Kore relay on a new thread.
+pub fn spawn(config: Config, state: Arc<Mutex<NetworkState>>, hooks: Arc<dyn SocketHooks>) -> RelayHandle {
+    let keep_running = Arc::new(AtomicBool::new(true));
+    let reconnect = Arc::new(AtomicBool::new(false));
+    let kore = KoreSlot::default();
+    let workers = Workers::default();
+    let (done_tx, done) = mpsc::channel();
+    let transports = transport::endpoints_from_config(&config);
+    state.lock().unwrap().limit_kore_queue(&config);
+
+    let thread = {
+        let threads = RelayThreads {
+            workers: workers.clone(),
+            hooks: hooks.clone(),
+            state: state.clone(),
+            woken: Arc::default(),
+        };
+        let control = Control { keep_running: keep_running.clone(), reconnect: reconnect.clone(), threads };
+        let (state, kore) = (state.clone(), kore.clone());
+        thread::spawn(move || {
+            hooks.on_relay_thread();
+            kore_connection_main(config, transports, state, hooks, kore, control);
+            let _ = done_tx.send(());
+        })
+    };
+
+    RelayHandle { keep_running, reconnect, done, thread, state, kore, workers }
+}
+
+/// Runs threads alongside the relay thread, for work that would otherwise
+/// hold it up: they are joined on shutdown and can wake the relay when they
+/// have something for it.
+#[derive(Clone)]
+pub struct RelayThreads {
+    workers: Workers,
+    pub(crate) hooks: Arc<dyn SocketHooks>,
+    pub(crate) state: Arc<Mutex<NetworkState>>,
+    woken: Arc<AtomicBool>,
+}
+
+impl RelayThreads {
+    /// Runs `work` on a new relay thread that is joined on shutdown.
+    pub(crate) fn spawn(&self, work: impl FnOnce() + Send + 'static) {
+        let hooks = self.hooks.clone();
+        let worker = thread::spawn(move || {
+            hooks.on_relay_thread();
+            work();
+        });
+        let mut workers = self.workers.lock().unwrap();
+        // Threads of connections that are gone have finished already.
+        workers.retain(|worker| !worker.is_finished());
+        workers.push(worker);
+    }
+
+    /// Has the relay thread look at the transports again.
+    pub(crate) fn wake(&self) {
+        self.woken.store(true, Ordering::SeqCst);
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.relay_wake.notify_one();
+    }
+}
+
+fn encode_frames(frames: VecDeque<XKoreFrame>) -> Vec<u8> {
+    let mut data = Vec::with_capacity(frames.iter().map(XKoreFrame::encoded_len).sum());
+    for frame in frames {
+        frame.encode_into(&mut data);
+    }
+    data
+}
+
+/// Writes `data` to Kore, giving up after `timeout`, and closes the connection.
+fn close_kore(mut client: Box<dyn KoreStream>, data: &[u8], timeout: Duration) -> bool {
+    let flushed = client.set_write_timeout(Some(timeout)).is_ok() && client.write_all(data).is_ok();
+    let _ = client.shutdown();
+    flushed
+}
+
+/// Flags the [`RelayHandle`] uses to steer the relay thread, and the threads
+/// it joins on shutdown.
+struct Control {
+    keep_running: Arc<AtomicBool>,
+    reconnect: Arc<AtomicBool>,
+    threads: RelayThreads,
+}
+
+fn kore_connection_main(
+    config: Config,
+    transports: Vec<Box<dyn KoreTransport>>,
+    state: Arc<Mutex<NetworkState>>,
+    hooks: Arc<dyn SocketHooks>,
+    kore: KoreSlot,
+    control: Control,
+) {
+    let mut last_ping = Instant::now();
+    let mut backoff = Backoff::new(config.reconnect_delay, config.reconnect_max_delay, config.reconnect_jitter);
+    let mut failover = Failover::new(transports.len(), config.kore_failback);
+    // The first attempt is made right away; after that it is set when the
+    // link goes down or an attempt fails.
+    let mut next_attempt = Some(Instant::now());
+    // Bound here rather than in `spawn`, which runs under the loader lock on
+    // Windows, where starting Winsock is not allowed.
+    let observers = ObserverListener::bind(&config, &control.threads);
+
+    while control.keep_running.load(Ordering::SeqCst) {
+        let link = state.lock().unwrap().link;
+        let reconnect = control.reconnect.swap(false, Ordering::SeqCst);
+        if reconnect {
+            backoff.reset();
+            next_attempt = Some(Instant::now());
+        }
+        control.threads.woken.store(false, Ordering::SeqCst);
+
+        // When to look again if nothing wakes the relay before.
+        let wake_at = match link {
+            LinkState::Disconnected => {
+                // Close whatever is left of a connection that was given up on
+                if let Some(old) = kore.lock().unwrap().take() {
+                    let _ = old.shutdown();
+                }
+
+                // With no attempt scheduled the link was just lost: a standby
+                // is tried right away, the same endpoint after a backoff.
+                let at = *next_attempt.get_or_insert_with(|| {
+                    if failover.link_lost() {
+                        Instant::now()
+                    } else {
+                        schedule_reconnect(&mut backoff)
+                    }
+                });
+                if Instant::now() < at {
+                    Some(at)
+                } else {
+                    let transport = transports[failover.current()].as_ref();
+                    match transport.ready(&control.threads) {
+                        Ok(true) => {
+                            match connect(&config, transport, &state, &control.threads) {
+                                Some(client) => {
+                                    *kore.lock().unwrap() = Some(client);
+                                    last_ping = Instant::now();
+                                    backoff.reset();
+                                    failover.connected();
+                                    next_attempt = None;
+                                }
+                                None => next_attempt = Some(next_endpoint(&mut failover, &mut backoff)),
+                            }
+                            Some(Instant::now())
+                        }
+                        // Still waiting for Kore to connect to us, which wakes
+                        // the relay
+                        Ok(false) => None,
+                        Err(e) => {
+                            println!("Cannot wait for X-Kore server on {}: {}", transport, e);
+                            next_attempt = Some(next_endpoint(&mut failover, &mut backoff));
+                            Some(Instant::now())
+                        }
+                    }
+                }
+            }
+            LinkState::Connected if reconnect => {
+                let mut state = state.lock().unwrap();
+                let id = state.link_id;
+                state.link_lost(id, "reconnect requested");
+                continue;
+            }
+            LinkState::Connected if failover.should_fail_back() => {
+                failover.fail_back();
+                next_attempt = Some(Instant::now());
+                let mut state = state.lock().unwrap();
+                let id = state.link_id;
+                state.link_lost(id, "failing back to the primary X-Kore server");
+                continue;
+            }
+            LinkState::Connected => {
+                if send_pending(&config, &state, &kore, &mut last_ping) {
+                    continue;
+                }
+                let ping_at = last_ping + config.ping_interval;
+                Some(failover.fail_back_at().map_or(ping_at, |at| ping_at.min(at)))
+            }
+            LinkState::Draining => {
+                pass_through(&state, hooks.as_ref(), "passing through");
+                continue;
+            }
+            // Only the relay thread connects, so this is never seen here.
+            LinkState::Connecting => None,
+        };
+        wait_for_work(&config, &state, &control, link, wake_at);
+    }
+
+    if let Some(observers) = &observers {
+        observers.close();
+    }
+    let data = {
+        let mut state = state.lock().unwrap();
+        state.set_link(LinkState::Draining, "shutting down");
+        for observer in state.observers.drain(..) {
+            observer.disconnect();
+        }
+        encode_frames(state.take_kore_frames())
+    };
+    let client = kore.lock().unwrap().take();
+    if let Some(client) = client {
+        if !close_kore(client, &data, FLUSH_TIMEOUT) {
+            println!("Could not flush pending data to X-Kore server");
+        }
+    }
+    pass_through(&state, hooks.as_ref(), "shut down");
+}
+
+/// Sends client data that was held back while the link was draining straight
+/// to the RO server, then lets the game talk to the server directly.
+fn pass_through(state: &Mutex<NetworkState>, hooks: &dyn SocketHooks, reason: &str) {
+    loop {
+        let (ro_server, data) = {
+            let mut state = state.lock().unwrap();
+            if state.server_buf.is_empty() {
+                state.set_link(LinkState::Disconnected, reason);
+                return;
+            }
+            (state.ro_server, std::mem::take(&mut state.server_buf))
+        };
+
+        match ro_server {
+            Some(socket) => {
+                if let Err(e) = hooks.send_all(socket, &data) {
+                    println!("Failed to pass {} bytes through to the RO server: {}", data.len(), e);
+                }
+            }
+            None => println!("No RO server connection yet, dropping {} bytes", data.len()),
+        }
+    }
+}
+
+/// Sleeps until `until`, or for as long as it takes if `None`, unless there
+/// is something to do before: frames queued for Kore, a change of the link,
+/// a request from the [`RelayHandle`] or a wakeup from one of the
+/// [`RelayThreads`]. A connected link is also looked at when Kore would time
+/// out.
+fn wait_for_work(config: &Config, state: &Mutex<NetworkState>, control: &Control, link: LinkState, until: Option<Instant>) {
+    let state = state.lock().unwrap();
+    let until = match link {
+        LinkState::Connected => until.map(|until| until.min(state.last_kore_frame + config.ping_timeout)),
+        _ => until,
+    };
+    let wake = state.relay_wake.clone();
+    let idle = |state: &mut NetworkState| {
+        state.link == link
+            && (link != LinkState::Connected || state.kore_queue.is_empty())
+            && control.keep_running.load(Ordering::SeqCst)
+            && !control.reconnect.load(Ordering::SeqCst)
+            && !control.threads.woken.load(Ordering::SeqCst)
+    };
+    match until {
+        Some(until) => {
+            let timeout = until.saturating_duration_since(Instant::now());
+            drop(wake.wait_timeout_while(state, timeout, idle).unwrap());
+        }
+        None => {
+            drop(wake.wait_while(state, idle).unwrap());
+        }
+    }
+}
+
+/// Moves on to the next endpoint after a failed attempt, backing off once
+/// all of them have failed.
+fn next_endpoint(failover: &mut Failover, backoff: &mut Backoff) -> Instant {
+    if failover.attempt_failed() {
+        schedule_reconnect(backoff)
+    } else {
+        Instant::now()
+    }
+}
+
+fn schedule_reconnect(backoff: &mut Backoff) -> Instant {
+    let delay = backoff.next_delay();
+    println!("Reconnecting to X-Kore server in {} ms", delay.as_millis());
+    Instant::now() + delay
+}
+
+/// Connects to Kore, runs the handshake and starts the reader thread for the
+/// new connection.
+fn connect(
+    config: &Config,
+    transport: &dyn KoreTransport,
+    state: &Arc<Mutex<NetworkState>>,
+    threads: &RelayThreads,
+) -> Option<Box<dyn KoreStream>> {
+    state.lock().unwrap().set_link(LinkState::Connecting, &format!("connecting to {}", transport));
+
+    let mut decoder = FrameDecoder::new();
+    let result = transport.connect().and_then(|mut client| {
+        match handshake(config, client.as_mut(), &mut decoder) {
+            Ok(protocol) => {
+                decoder.accept_compressed(protocol.is_some_and(|hello| hello.features.contains(Features::COMPRESSION)));
+                // A Kore that stops reading must not hold up the relay
+                // thread, which also does the pass-through.
+                client.set_write_timeout(Some(config.ping_timeout))?;
+                Ok((client.try_clone()?, client, protocol))
+            }
+            Err(e) => {
+                let _ = client.shutdown();
+                Err(e)
+            }
+        }
+    });
+    let (reader, client, protocol) = match result {
+        Ok(connection) => connection,
+        Err(e) => {
+            state.lock().unwrap().set_link(LinkState::Disconnected, &e.to_string());
+            return None;
+        }
+    };
+
+    let id = state.lock().unwrap().link_up(&transport.to_string(), protocol);
+
+    let (state, hooks) = (state.clone(), threads.hooks.clone());
+    threads.spawn(move || kore_reader_main(reader, decoder, id, &state, hooks.as_ref()));
+    Some(client)
+}
+
+/// Authenticates Kore if a secret is set, then agrees on a protocol.
+fn handshake(config: &Config, client: &mut dyn KoreStream, decoder: &mut FrameDecoder) -> io::Result<Option<Hello>> {
+    if let Some(secret) = &config.kore_secret {
+        handshake::authenticate(client, decoder, secret.as_bytes(), config.ping_timeout)?;
+    }
+    if !config.kore_hello {
+        return Ok(None);
+    }
+
+    let protocol = handshake::negotiate(client, decoder, offered_features(config), config.hello_timeout)?;
+    match protocol {
+        Some(hello) => println!("X-Kore protocol v{} (features {:#x})", hello.version, hello.features.0),
+        None => println!("X-Kore server did not answer hello, using the legacy protocol"),
+    }
+    Ok(protocol)
+}
+
+/// The features this build implements that the config asks for.
+fn offered_features(config: &Config) -> Features {
+    let mut features = Features::NONE;
+    if config.kore_timestamps {
+        features = features | Features::TIMESTAMPS;
+    }
+    if config.kore_compression {
+        features = features | Features::COMPRESSION;
+    }
+    features.intersect(Features::SUPPORTED)
+}
+
+/// Writes queued frames and pings to a connected Kore, and checks that Kore is
+/// still answering. Returns `true` if the link was lost.
+fn send_pending(config: &Config, state: &Mutex<NetworkState>, kore: &KoreSlot, last_ping: &mut Instant) -> bool {
+    let ping_needed = last_ping.elapsed() > config.ping_interval;
+    let (id, frames, compressed, dead_at) = {
+        let mut state = state.lock().unwrap();
+        let dead_at = state.last_kore_frame + config.ping_timeout;
+        if Instant::now() >= dead_at {
+            let id = state.link_id;
+            state.link_lost(id, "ping timeout");
+            return true;
+        }
+        if ping_needed && state.ping_sent.is_none() {
+            state.ping_sent = Some(Instant::now());
+        }
+        (state.link_id, state.take_kore_frames(), state.agreed(Features::COMPRESSION), dead_at)
+    };
+    let mut data = encode_frames(frames);
+    if compressed {
+        let mut packed = Vec::with_capacity(data.len());
+        compress::compress_into(&data, &mut packed);
+        data = packed;
+    }
+
+    let result = match kore.lock().unwrap().as_mut() {
+        Some(client) => {
+            // A write Kore does not take in time is as good as a missed
+            // keep-alive, so it may not outlast the ping timeout either.
+            let timeout = dead_at.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+            let mut result = client.set_write_timeout(Some(timeout)).and_then(|()| client.write_all(&data));
+            if result.is_ok() && ping_needed {
+                result = client.write_all(&XKoreFrame::KeepAlive.encode());
+                *last_ping = Instant::now();
+            }
+            result
+        }
+        None => Ok(()),
+    };
+
+    match result {
+        Ok(()) => false,
+        Err(e) => {
+            state.lock().unwrap().link_lost(id, &format!("write failed: {}", e));
+            true
+        }
+    }
+}
+
+/// Reads frames from Kore until the connection is closed.
+fn kore_reader_main(
+    mut client: Box<dyn KoreStream>,
+    mut decoder: FrameDecoder,
+    id: u64,
+    state: &Mutex<NetworkState>,
+    hooks: &dyn SocketHooks,
+) {
+    let mut buf = [0u8; BUF_SIZE];
+
+    let reason = loop {
+        // The handshake may have left frames in the decoder already.
+        while let Some(frame) = decoder.next_frame() {
+            if !process_packet(frame, id, state, hooks) {
+                // The link this connection served is gone; what Kore still
+                // sends on it is stale.
+                return;
+            }
+        }
+        match client.read(&mut buf) {
+            Ok(0) => break "closed by Kore".to_string(),
+            Ok(n) => decoder.extend(&buf[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => break format!("read failed: {}", e),
+        }
+    };
+    state.lock().unwrap().link_lost(id, &reason);
+}
+
+/// Applies a frame Kore sent on link `id`, unless that link is no longer
+/// up. Returns `false` if it is not.
+fn process_packet(frame: XKoreFrame, id: u64, state: &Mutex<NetworkState>, hooks: &dyn SocketHooks) -> bool {
+    let mut state = state.lock().unwrap();
+    if state.link_id != id || state.link != LinkState::Connected {
+        return false;
+    }
+    state.last_kore_frame = Instant::now();
+
+    match frame {
+        XKoreFrame::Sent(data) => {
+            println!("Sending data from OpenKore to Server");
+            let ro_server = state.ro_server;
+            drop(state);
+            match ro_server {
+                Some(socket) => {
+                    if let Err(e) = hooks.send_all(socket, &data) {
+                        println!("Failed to send {} bytes to the RO server: {}", data.len(), e);
+                    }
+                }
+                None => println!("No RO server connection yet, dropping {} bytes", data.len()),
+            }
+        },
+        XKoreFrame::Received(data) => {
+            println!("Sending data from OpenKore to Client");
+            queue_client_data(&mut state, &data);
+        },
+        XKoreFrame::KeepAlive => {
+            if let Some(sent) = state.ping_sent.take() {
+                state.kore_rtt = Some(sent.elapsed());
+            }
+            let endpoint = state.kore_endpoint().unwrap_or("X-Kore server");
+            match state.kore_rtt {
+                Some(rtt) => println!("Received Keep-Alive Packet from {} (rtt {} ms)", endpoint, rtt.as_millis()),
+                None => println!("Received Keep-Alive Packet from {}", endpoint),
+            }
+        },
+        XKoreFrame::Auth(_) | XKoreFrame::Hello(_) => {
+            println!("Ignoring X-Kore handshake frame (type {}) after the handshake", frame.kind() as char);
+        }
+        XKoreFrame::Timestamp(_) | XKoreFrame::Compressed(_) => {}
+        XKoreFrame::Unknown { kind, payload } => {
+            println!("Skipping malformed X-Kore frame (type {:#04x}, {} bytes)", kind, payload.len());
+        }
+    }
+    true
+}
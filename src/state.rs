@@ -0,0 +1,433 @@
+//! Shared state between the hooked socket functions and the Kore relay, and
+//! the routing decisions made on every `recv`/`send` of the game client.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+
+use crate::config::{Config, OverflowPolicy, DEFAULT_KORE_QUEUE_LIMIT, DEFAULT_PING_TIMEOUT};
+use crate::frame::{Stamp, XKoreFrame};
+use crate::handshake::{Features, Hello};
+use crate::observer::Observer;
+
+/// A platform socket handle: a `SOCKET` on Windows, a file descriptor elsewhere.
+pub type RawSocket = usize;
+
+/// The state of the link to OpenKore.
+///
+/// ```text
+/// Disconnected -> Connecting -> Connected -> Draining -> Disconnected
+///                      |            |
+///                      +------------+--> Disconnected
+/// ```
+///
+/// A connection attempt either succeeds or falls back to `Disconnected`. A
+/// connected link is `Draining` while what is still queued gets flushed: to
+/// Kore when the relay shuts down, or straight to the RO server when Kore is
+/// lost to a read EOF or error, a failed write or a ping timeout. Game traffic
+/// only goes through Kore while the link is `Connected` and passes straight
+/// through to the server once it is `Disconnected`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Disconnected,
+    Connecting,
+    Connected,
+    Draining,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Captured traffic that was dropped because the queue for Kore was full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueDrops {
+    pub frames: u64,
+    /// Payload bytes, i.e. game traffic without frame headers.
+    pub bytes: u64,
+}
+
+pub struct NetworkState {
+    /// The socket the game client last exchanged data with the RO server on.
+    pub(crate) ro_server: Option<RawSocket>,
+    pub(crate) link: LinkState,
+    /// Identifies the current connection, so a reader of an older one cannot
+    /// change the state of a newer one.
+    pub(crate) link_id: u64,
+    /// When the last frame from Kore arrived.
+    pub(crate) last_kore_frame: Instant,
+    /// When the keep-alive Kore has not answered yet was sent.
+    pub(crate) ping_sent: Option<Instant>,
+    /// Round-trip time of the last answered keep-alive.
+    pub(crate) kore_rtt: Option<Duration>,
+    /// Where the current connection goes, as the transport names it.
+    pub(crate) endpoint: Option<String>,
+    /// What was agreed with Kore in the hello, `None` for the legacy protocol.
+    pub(crate) protocol: Option<Hello>,
+    /// Start of the clock used for timestamps.
+    pub(crate) epoch: Instant,
+    /// Sequence numbers of the next captured `R` and `S` frames.
+    pub(crate) next_received: u32,
+    pub(crate) next_sent: u32,
+    /// Data OpenKore wants delivered to the game client.
+    pub(crate) send_buf: Vec<u8>,
+    /// Frames waiting to be written to OpenKore.
+    pub(crate) kore_queue: VecDeque<XKoreFrame>,
+    /// Encoded size of everything in `kore_queue`.
+    pub(crate) kore_queued: usize,
+    pub(crate) queue_limit: usize,
+    pub(crate) overflow: OverflowPolicy,
+    /// How long a game thread waits for room under [`OverflowPolicy::Block`].
+    pub(crate) block_timeout: Duration,
+    pub(crate) dropped: QueueDrops,
+    /// Whether frames were dropped on the current connection, so the first
+    /// drop is reported and the rest are only counted.
+    pub(crate) dropping: bool,
+    /// Signalled when `kore_queue` is emptied or the link changes.
+    pub(crate) kore_space: Arc<Condvar>,
+    /// Signalled when there is work for the relay thread: frames queued for
+    /// Kore or a change of the link.
+    pub(crate) relay_wake: Arc<Condvar>,
+    /// Client data that was meant for OpenKore when the link went down, to be
+    /// passed straight to the RO server before anything the client sends next.
+    pub(crate) server_buf: Vec<u8>,
+    /// Connected read-only observers.
+    pub(crate) observers: Vec<Arc<Observer>>,
+}
+
+impl NetworkState {
+    pub fn new() -> Self {
+        NetworkState {
+            ro_server: None,
+            link: LinkState::Disconnected,
+            link_id: 0,
+            last_kore_frame: Instant::now(),
+            ping_sent: None,
+            kore_rtt: None,
+            endpoint: None,
+            protocol: None,
+            epoch: Instant::now(),
+            next_received: 0,
+            next_sent: 0,
+            send_buf: Vec::new(),
+            kore_queue: VecDeque::new(),
+            kore_queued: 0,
+            queue_limit: DEFAULT_KORE_QUEUE_LIMIT,
+            overflow: OverflowPolicy::Disconnect,
+            block_timeout: DEFAULT_PING_TIMEOUT,
+            dropped: QueueDrops::default(),
+            dropping: false,
+            kore_space: Arc::new(Condvar::new()),
+            relay_wake: Arc::new(Condvar::new()),
+            server_buf: Vec::new(),
+            observers: Vec::new(),
+        }
+    }
+
+    pub fn link_state(&self) -> LinkState {
+        self.link
+    }
+
+    pub(crate) fn set_link(&mut self, link: LinkState, reason: &str) {
+        if self.link != link {
+            println!("X-Kore link {} -> {} ({})", self.link, link, reason);
+            self.link = link;
+            self.kore_space.notify_all();
+            self.relay_wake.notify_one();
+        }
+    }
+
+    /// Applies the queue settings from `config`.
+    pub(crate) fn limit_kore_queue(&mut self, config: &Config) {
+        self.queue_limit = config.kore_queue_limit;
+        self.overflow = config.kore_overflow;
+        self.block_timeout = config.ping_timeout;
+    }
+
+    /// Removes everything queued for Kore, making room for the game again.
+    pub(crate) fn take_kore_frames(&mut self) -> VecDeque<XKoreFrame> {
+        self.kore_queued = 0;
+        self.kore_space.notify_all();
+        std::mem::take(&mut self.kore_queue)
+    }
+
+    fn kore_queue_full(&self) -> bool {
+        self.link == LinkState::Connected && self.overflow == OverflowPolicy::Block && self.kore_queued >= self.queue_limit
+    }
+
+    fn drop_frame(&mut self, frame: &XKoreFrame) {
+        if !self.dropping {
+            println!("X-Kore queue full, dropping frames from the server");
+            self.dropping = true;
+        }
+        self.dropped.frames += 1;
+        self.dropped.bytes += frame.payload().len() as u64;
+    }
+
+    /// Drops the oldest queued `R` frame together with its timestamp, if
+    /// any. Client data is never dropped.
+    fn drop_oldest(&mut self) -> bool {
+        let Some(index) = self.kore_queue.iter().position(|frame| matches!(frame, XKoreFrame::Received(_))) else {
+            return false;
+        };
+        let old = self.kore_queue.remove(index).unwrap();
+        self.kore_queued -= old.encoded_len();
+        self.drop_frame(&old);
+        if index > 0 && matches!(self.kore_queue[index - 1], XKoreFrame::Timestamp(_)) {
+            let stamp = self.kore_queue.remove(index - 1).unwrap();
+            self.kore_queued -= stamp.encoded_len();
+        }
+        true
+    }
+
+    /// Stamps a captured frame if Kore asked for timestamps.
+    fn stamp(&mut self, frame: &XKoreFrame) -> Option<XKoreFrame> {
+        let sequence = match frame {
+            XKoreFrame::Received(_) => &mut self.next_received,
+            XKoreFrame::Sent(_) => &mut self.next_sent,
+            _ => return None,
+        };
+        let stamp = Stamp {
+            direction: frame.kind(),
+            sequence: *sequence,
+            micros: self.epoch.elapsed().as_micros() as u64,
+        };
+        *sequence = sequence.wrapping_add(1);
+
+        self.agreed(Features::TIMESTAMPS).then(|| stamp.to_frame())
+    }
+
+    /// Copies captured traffic to every observer, forgetting those that have
+    /// gone.
+    fn observe(&mut self, frame: &XKoreFrame) {
+        self.observers.retain(|observer| observer.push(frame));
+    }
+
+    /// Starts a new connection to `endpoint` speaking `protocol`, returning
+    /// its id.
+    pub(crate) fn link_up(&mut self, endpoint: &str, protocol: Option<Hello>) -> u64 {
+        self.link_id += 1;
+        self.last_kore_frame = Instant::now();
+        self.ping_sent = None;
+        self.kore_rtt = None;
+        self.endpoint = Some(endpoint.to_string());
+        self.protocol = protocol;
+        self.dropping = false;
+        self.set_link(LinkState::Connected, &format!("connected to {}", endpoint));
+        self.link_id
+    }
+
+    /// Marks connection `id` as dead if it is still the connected one.
+    ///
+    /// Client data still waiting for Kore is kept for the RO server, so the
+    /// game does not lose what it sent just before Kore went away.
+    pub(crate) fn link_lost(&mut self, id: u64, reason: &str) {
+        if self.link_id == id && self.link == LinkState::Connected {
+            self.set_link(LinkState::Draining, reason);
+
+            for frame in self.take_kore_frames() {
+                if let XKoreFrame::Sent(data) = frame {
+                    self.server_buf.extend_from_slice(&data);
+                }
+            }
+        }
+    }
+
+    /// How long ago Kore last sent a frame on the current connection.
+    pub fn kore_silence(&self) -> Duration {
+        self.last_kore_frame.elapsed()
+    }
+
+    /// Round-trip time from our last answered keep-alive to Kore's answer.
+    pub fn kore_rtt(&self) -> Option<Duration> {
+        self.kore_rtt
+    }
+
+    /// The endpoint Kore is connected on, while the link is up.
+    pub fn kore_endpoint(&self) -> Option<&str> {
+        match self.link {
+            LinkState::Connected => self.endpoint.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether Kore agreed to use `feature` on the current connection.
+    pub(crate) fn agreed(&self, feature: Features) -> bool {
+        self.protocol.is_some_and(|hello| hello.features.contains(feature))
+    }
+
+    /// The protocol version and features agreed with Kore, or `None` if Kore
+    /// speaks the legacy protocol.
+    pub fn kore_protocol(&self) -> Option<Hello> {
+        self.protocol
+    }
+
+    pub fn ro_server(&self) -> Option<RawSocket> {
+        self.ro_server
+    }
+
+    /// Bytes queued for the game client that it has not read yet.
+    pub fn client_data(&self) -> &[u8] {
+        &self.send_buf
+    }
+
+    /// Frames queued for OpenKore that have not been written yet.
+    pub fn kore_frames(&self) -> &VecDeque<XKoreFrame> {
+        &self.kore_queue
+    }
+
+    /// Encoded size of the frames queued for OpenKore.
+    pub fn kore_queued(&self) -> usize {
+        self.kore_queued
+    }
+
+    /// What the overflow policy has dropped so far.
+    pub fn kore_dropped(&self) -> QueueDrops {
+        self.dropped
+    }
+
+    /// Observers that were connected when traffic was last captured.
+    pub fn observers(&self) -> &[Arc<Observer>] {
+        &self.observers
+    }
+
+    /// Client data waiting to be passed straight to the RO server.
+    pub fn server_data(&self) -> &[u8] {
+        &self.server_buf
+    }
+}
+
+impl Default for NetworkState {
+    fn default() -> Self {
+        NetworkState::new()
+    }
+}
+
+// Global state wrapped in mutex
+lazy_static! {
+    pub static ref NETWORK_STATE: Arc<Mutex<NetworkState>> = Arc::new(Mutex::new(NetworkState::new()));
+}
+
+/// Where a buffer passed to the hooked `send` has to go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendRoute {
+    /// The data was queued for OpenKore, which decides whether to forward it.
+    Kore,
+    /// The data was queued for the RO server behind data that was meant for
+    /// Kore when the link went down.
+    Held,
+    /// The data must be sent straight to the RO server.
+    Direct,
+}
+
+/// Queues data from OpenKore to be returned by the client's next `recv`.
+pub fn queue_client_data(state: &mut NetworkState, data: &[u8]) {
+    state.send_buf.extend_from_slice(data);
+}
+
+/// Copies as much queued client-bound data as fits into `out`.
+///
+/// Whatever does not fit stays queued for the next `recv`. With `peek` set the
+/// data is copied but left in the queue, matching `MSG_PEEK`.
+pub fn take_client_data(state: &mut NetworkState, out: &mut [u8], peek: bool) -> usize {
+    let n = state.send_buf.len().min(out.len());
+    out[..n].copy_from_slice(&state.send_buf[..n]);
+    if !peek {
+        state.send_buf.drain(..n);
+    }
+    n
+}
+
+/// Records data the game client received from the RO server on `socket`.
+pub fn capture_recv(state: &mut NetworkState, socket: RawSocket, data: &[u8]) {
+    state.ro_server = Some(socket);
+    let frame = XKoreFrame::Received(data.to_vec());
+    state.observe(&frame);
+    send_data_to_kore(state, frame);
+}
+
+/// Waits until the queue for Kore has room again when the overflow policy is
+/// [`OverflowPolicy::Block`]; returns right away otherwise.
+///
+/// Hooks call this before capturing traffic. If the relay does not catch up
+/// within the ping timeout, Kore is given up on so the game can carry on.
+pub fn wait_for_kore_space(state: MutexGuard<'_, NetworkState>) -> MutexGuard<'_, NetworkState> {
+    let space = state.kore_space.clone();
+    let timeout = state.block_timeout;
+    let (mut state, result) = space.wait_timeout_while(state, timeout, |state| state.kore_queue_full()).unwrap();
+    if result.timed_out() && state.kore_queue_full() {
+        let id = state.link_id;
+        state.link_lost(id, "X-Kore queue stayed full");
+    }
+    state
+}
+
+/// Decides where data the game client sends on `socket` goes, queueing it for
+/// OpenKore when the link is up.
+pub fn route_send(state: &mut NetworkState, socket: RawSocket, data: &[u8]) -> SendRoute {
+    state.ro_server = Some(socket);
+    let frame = XKoreFrame::Sent(data.to_vec());
+    state.observe(&frame);
+    if state.link == LinkState::Connected {
+        send_data_to_kore(state, frame);
+    }
+    match state.link {
+        LinkState::Connected => SendRoute::Kore,
+        LinkState::Draining => {
+            state.server_buf.extend_from_slice(data);
+            SendRoute::Held
+        }
+        LinkState::Disconnected | LinkState::Connecting => SendRoute::Direct,
+    }
+}
+
+/// Queues `frame` for Kore, applying the overflow policy if it does not fit.
+///
+/// Under [`OverflowPolicy::Block`] the frame is always queued, since the hook
+/// has already waited for room with [`wait_for_kore_space`]. The drop
+/// policies only ever drop `R` frames: client data that cannot be queued
+/// gives up on Kore as [`OverflowPolicy::Disconnect`] does, so it reaches
+/// the server rather than being lost.
+pub(crate) fn send_data_to_kore(state: &mut NetworkState, frame: XKoreFrame) {
+    if state.link != LinkState::Connected {
+        return;
+    }
+
+    let stamp = state.stamp(&frame);
+    let len = frame.encoded_len() + stamp.as_ref().map_or(0, XKoreFrame::encoded_len);
+    if state.kore_queued + len > state.queue_limit {
+        match state.overflow {
+            OverflowPolicy::Block => {}
+            OverflowPolicy::DropNewest if matches!(frame, XKoreFrame::Received(_)) => {
+                state.drop_frame(&frame);
+                return;
+            }
+            OverflowPolicy::DropOldest | OverflowPolicy::DropNewest => {
+                while state.kore_queued + len > state.queue_limit && state.drop_oldest() {}
+                if state.kore_queued + len > state.queue_limit {
+                    if matches!(frame, XKoreFrame::Received(_)) {
+                        state.drop_frame(&frame);
+                    } else {
+                        let id = state.link_id;
+                        state.link_lost(id, "X-Kore queue full of client data");
+                    }
+                    return;
+                }
+            }
+            OverflowPolicy::Disconnect => {
+                let id = state.link_id;
+                state.link_lost(id, "X-Kore queue full");
+                return;
+            }
+        }
+    }
+
+    state.kore_queued += len;
+    state.kore_queue.extend(stamp);
+    state.kore_queue.push_back(frame);
+    state.relay_wake.notify_one();
+}
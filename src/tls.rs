@@ -0,0 +1,278 @@
+//! TLS for the Kore link, for when OpenKore runs on another machine.
+//!
+//! There is no certificate authority involved: Kore's certificate is pinned
+//! in the config and the handshake only succeeds if Kore presents exactly
+//! that certificate.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, ring, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme};
+
+use crate::config::Config;
+use crate::relay::RelayThreads;
+use crate::transport::{KoreStream, KoreTransport};
+
+/// Size of the buffer ciphertext is read into.
+const TLS_BUF_SIZE: usize = 16 * 1024;
+
+/// Runs TLS over another transport.
+pub struct TlsTransport {
+    inner: Box<dyn KoreTransport>,
+    server_name: String,
+    cert: Option<PathBuf>,
+    /// How long the handshake may take.
+    timeout: Duration,
+}
+
+impl TlsTransport {
+    /// `server_name` is what Kore is asked for in SNI; the certificate is
+    /// checked against the pinned one only.
+    pub fn new(inner: Box<dyn KoreTransport>, server_name: &str, config: &Config) -> Self {
+        TlsTransport {
+            inner,
+            server_name: server_name.to_string(),
+            cert: config.kore_tls_cert.clone(),
+            timeout: config.ping_timeout,
+        }
+    }
+
+    /// Reads the pinned certificate. It is read on every connection attempt,
+    /// so a replaced certificate is picked up without restarting the game.
+    fn client_config(&self) -> io::Result<Arc<ClientConfig>> {
+        let path = self.cert.as_ref().ok_or_else(|| invalid_input("kore_tls_cert is not set"))?;
+        let data = fs::read(path)?;
+        let cert = match CertificateDer::from_pem_slice(&data) {
+            Ok(cert) => cert,
+            Err(_) => CertificateDer::from(data),
+        };
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier = PinnedCert { cert, algorithms: provider.signature_verification_algorithms };
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Ok(Arc::new(config))
+    }
+}
+
+impl KoreTransport for TlsTransport {
+    fn ready(&self, relay: &RelayThreads) -> io::Result<bool> {
+        self.inner.ready(relay)
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn KoreStream>> {
+        let name = ServerName::try_from(self.server_name.clone())
+            .map_err(|_| invalid_input(&format!("`{}` is not a valid TLS server name", self.server_name)))?;
+        let mut conn = ClientConnection::new(self.client_config()?, name).map_err(tls_error)?;
+
+        let mut sock = self.inner.connect()?;
+        sock.set_read_timeout(Some(self.timeout))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        sock.set_read_timeout(None)?;
+
+        Ok(Box::new(TlsStream {
+            conn: Arc::new(Mutex::new(conn)),
+            sending: Arc::default(),
+            sock,
+            pending: Vec::new(),
+        }))
+    }
+}
+
+impl fmt::Display for TlsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tls+{}", self.inner)
+    }
+}
+
+/// Accepts only the pinned certificate, but still checks that Kore holds
+/// its private key.
+#[derive(Debug)]
+struct PinnedCert {
+    cert: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// A TLS connection shared by the relay's writer and reader handles.
+///
+/// Neither handle holds the connection while it waits on the socket: the
+/// reader waits for ciphertext without it, so a quiet Kore never keeps the
+/// writer from sending, and the writer encodes records under it but writes
+/// them out after, so a Kore that stops reading never keeps the reader from
+/// taking what Kore sends.
+struct TlsStream {
+    conn: Arc<Mutex<ClientConnection>>,
+    /// Held while records are encoded and written out, so they reach the
+    /// socket in the order they were encoded.
+    sending: Arc<Mutex<()>>,
+    sock: Box<dyn KoreStream>,
+    /// Ciphertext read from the socket that rustls has not taken yet.
+    pending: Vec<u8>,
+}
+
+impl TlsStream {
+    /// Takes the records the connection has queued.
+    fn take_records(conn: &mut ClientConnection) -> io::Result<Vec<u8>> {
+        let mut records = Vec::new();
+        while conn.wants_write() {
+            conn.write_tls(&mut records)?;
+        }
+        Ok(records)
+    }
+
+    /// Writes out records taken while `sending` was held.
+    fn send(sock: &mut dyn KoreStream, records: &[u8]) -> io::Result<()> {
+        sock.write_all(records)?;
+        sock.flush()
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0u8; TLS_BUF_SIZE];
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            if self.pending.is_empty() {
+                let n = self.sock.read(&mut raw)?;
+                self.pending.extend_from_slice(&raw[..n]);
+            }
+            // rustls may take only part of what was read, so the rest waits
+            // until the plaintext it yields has been read. An empty read
+            // tells rustls the peer has gone.
+            let mut conn = self.conn.lock().unwrap();
+            let taken = conn.read_tls(&mut &self.pending[..])?;
+            self.pending.drain(..taken);
+            let wants_write = conn.process_new_packets().map_err(tls_error)?.tls_bytes_to_write() > 0;
+            drop(conn);
+
+            // Records rustls answers with are left for the writer if it is
+            // busy: the reader must not wait on a Kore that is not reading.
+            if wants_write {
+                if let Ok(_sending) = self.sending.try_lock() {
+                    let records = Self::take_records(&mut self.conn.lock().unwrap())?;
+                    Self::send(self.sock.as_mut(), &records)?;
+                }
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let _sending = self.sending.lock().unwrap();
+        let (n, records) = {
+            let mut conn = self.conn.lock().unwrap();
+            let n = conn.writer().write(data)?;
+            (n, Self::take_records(&mut conn)?)
+        };
+        Self::send(self.sock.as_mut(), &records)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _sending = self.sending.lock().unwrap();
+        let records = {
+            let mut conn = self.conn.lock().unwrap();
+            conn.writer().flush()?;
+            Self::take_records(&mut conn)?
+        };
+        Self::send(self.sock.as_mut(), &records)
+    }
+}
+
+impl KoreStream for TlsStream {
+    fn try_clone(&self) -> io::Result<Box<dyn KoreStream>> {
+        Ok(Box::new(TlsStream {
+            conn: self.conn.clone(),
+            sending: self.sending.clone(),
+            sock: self.sock.try_clone()?,
+            pending: Vec::new(),
+        }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        // Say goodbye if the connection is free; a thread killed during
+        // process exit may never release it.
+        if let (Ok(_sending), Ok(mut conn)) = (self.sending.try_lock(), self.conn.try_lock()) {
+            conn.send_close_notify();
+            let records = Self::take_records(&mut conn);
+            drop(conn);
+            let mut sock = self.sock.try_clone()?;
+            let _ = records.and_then(|records| Self::send(sock.as_mut(), &records));
+        }
+        self.sock.shutdown()
+    }
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
@@ -0,0 +1,326 @@
+//! How the relay reaches OpenKore.
+//!
+//! The relay only sees a [`KoreTransport`] that opens connections and the
+//! [`KoreStream`] handles it returns, so the kind of link is picked in the
+//! config rather than in the relay.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(windows)]
+use uds_windows::UnixStream;
+
+use crate::config::{AddressFamily, Config, Endpoint, TransportKind};
+use crate::relay::RelayThreads;
+use crate::tls::TlsTransport;
+
+/// An open connection to Kore.
+pub trait KoreStream: Read + Write + Send {
+    /// Opens another handle on the same connection, for the reader thread.
+    fn try_clone(&self) -> io::Result<Box<dyn KoreStream>>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Closes the connection in both directions, which also wakes a reader
+    /// blocked on another handle.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+/// A way of connecting to Kore. `Display` names the endpoint for log output.
+pub trait KoreTransport: fmt::Display + Send {
+    /// Whether [`connect`](KoreTransport::connect) can be called now.
+    /// Transports that wait for Kore to connect return `false` until it has,
+    /// without blocking, and wait on one of the `relay` threads, which wakes
+    /// the relay once Kore is there.
+    fn ready(&self, _relay: &RelayThreads) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn KoreStream>>;
+}
+
+/// Builds the transport selected by `kore_transport`, wrapped in TLS when
+/// `kore_tls` is set.
+pub fn from_config(config: &Config) -> Box<dyn KoreTransport> {
+    let transport: Box<dyn KoreTransport> = match config.kore_transport {
+        TransportKind::Tcp => Box::new(TcpTransport::new(&config.kore_host, config.kore_port, config)),
+        TransportKind::Unix => Box::new(UnixTransport { path: config.kore_path.clone() }),
+        TransportKind::Listen => {
+            Box::new(ListenTransport::new(TcpTransport::new(&config.kore_host, config.kore_port, config)))
+        }
+    };
+    with_tls(transport, &config.kore_host, config)
+}
+
+/// Builds the transports for the primary endpoint and then every
+/// `kore_failover` endpoint, in order.
+pub fn endpoints_from_config(config: &Config) -> Vec<Box<dyn KoreTransport>> {
+    let mut transports = vec![from_config(config)];
+    for endpoint in &config.kore_failover {
+        transports.push(match endpoint {
+            Endpoint::Tcp { host, port } => with_tls(Box::new(TcpTransport::new(host, *port, config)), host, config),
+            Endpoint::Unix(path) => with_tls(Box::new(UnixTransport { path: path.clone() }), &config.kore_host, config),
+        });
+    }
+    transports
+}
+
+fn with_tls(transport: Box<dyn KoreTransport>, server_name: &str, config: &Config) -> Box<dyn KoreTransport> {
+    if config.kore_tls {
+        Box::new(TlsTransport::new(transport, server_name, config))
+    } else {
+        transport
+    }
+}
+
+/// Connects over TCP, resolving the host again on every attempt.
+pub struct TcpTransport {
+    pub host: String,
+    pub port: u16,
+    pub family: AddressFamily,
+    /// How long connecting to one of the addresses may take.
+    pub timeout: Duration,
+}
+
+impl TcpTransport {
+    /// Reaches `host`:`port` over `kore_family`, each connection attempt
+    /// bounded by `ping_timeout`.
+    pub fn new(host: &str, port: u16, config: &Config) -> Self {
+        TcpTransport { host: host.to_string(), port, family: config.kore_family, timeout: config.ping_timeout }
+    }
+
+    /// Resolves the endpoint, keeping only addresses of the configured family.
+    pub fn addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .filter(|addr| self.family.matches(addr))
+            .collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} has no {:?} address", self.host, self.family),
+            ));
+        }
+        Ok(addrs)
+    }
+}
+
+impl KoreTransport for TcpTransport {
+    /// Tries each address in turn, so one that drops the connection attempt
+    /// costs at most `timeout`.
+    fn connect(&self) -> io::Result<Box<dyn KoreStream>> {
+        let mut last_error = None;
+        for addr in self.addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => return Ok(Box::new(stream)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("addrs returned no address"))
+    }
+}
+
+impl fmt::Display for TcpTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tcp://{}:{}", self.host, self.port)
+    }
+}
+
+impl KoreStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn KoreStream>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// Waits for Kore to connect over TCP, for setups where Kore starts after
+/// the game and cannot be reached on a fixed address.
+///
+/// The address is bound on the first attempt and kept for later
+/// connections; if binding fails it is tried again after the reconnect delay.
+/// Only one Kore is served at a time, others wait until it has gone.
+pub struct ListenTransport {
+    addr: TcpTransport,
+    acceptor: RefCell<Option<Acceptor>>,
+    wait: Arc<Mutex<Wait>>,
+}
+
+/// Where waiting for Kore to connect has got to.
+enum Wait {
+    Idle,
+    /// A relay thread is waiting for Kore.
+    Waiting,
+    Done(io::Result<TcpStream>),
+}
+
+impl ListenTransport {
+    pub fn new(addr: TcpTransport) -> Self {
+        ListenTransport { addr, acceptor: RefCell::new(None), wait: Arc::new(Mutex::new(Wait::Idle)) }
+    }
+}
+
+impl KoreTransport for ListenTransport {
+    fn ready(&self, relay: &RelayThreads) -> io::Result<bool> {
+        let mut wait = self.wait.lock().unwrap();
+        match std::mem::replace(&mut *wait, Wait::Idle) {
+            Wait::Idle => {}
+            Wait::Done(Err(e)) => return Err(e),
+            other => {
+                let connected = matches!(other, Wait::Done(_));
+                *wait = other;
+                return Ok(connected);
+            }
+        }
+
+        let mut acceptor = self.acceptor.borrow_mut();
+        if acceptor.is_none() {
+            let bound = TcpListener::bind(&self.addr.addrs()?[..])?;
+            println!("Waiting for X-Kore server on {}", bound.local_addr()?);
+            *acceptor = Some(Acceptor::new(bound));
+        }
+
+        *wait = Wait::Waiting;
+        let (acceptor, wait, waker) = (acceptor.clone().unwrap(), self.wait.clone(), relay.clone());
+        relay.spawn(move || {
+            let done = match acceptor.accept() {
+                Ok(Some((client, peer))) => {
+                    println!("X-Kore server connected from {}", peer);
+                    Ok(client)
+                }
+                Ok(None) => return,
+                Err(e) => Err(e),
+            };
+            *wait.lock().unwrap() = Wait::Done(done);
+            waker.wake();
+        });
+        Ok(false)
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn KoreStream>> {
+        let mut wait = self.wait.lock().unwrap();
+        match std::mem::replace(&mut *wait, Wait::Idle) {
+            Wait::Done(Ok(client)) => Ok(Box::new(client)),
+            other => {
+                *wait = other;
+                Err(io::Error::new(io::ErrorKind::NotConnected, "no X-Kore server has connected"))
+            }
+        }
+    }
+}
+
+impl Drop for ListenTransport {
+    fn drop(&mut self) {
+        if let Some(acceptor) = self.acceptor.get_mut() {
+            acceptor.close();
+        }
+    }
+}
+
+/// A blocking listener a relay thread can wait on for connections, so the
+/// relay does not have to poll for them.
+#[derive(Clone)]
+pub(crate) struct Acceptor {
+    listener: Arc<TcpListener>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Acceptor {
+    pub(crate) fn new(listener: TcpListener) -> Self {
+        Acceptor { listener: Arc::new(listener), closed: Arc::default() }
+    }
+
+    /// Waits for the next connection, returning `None` once closed.
+    pub(crate) fn accept(&self) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        loop {
+            let accepted = self.listener.accept();
+            if self.closed.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            match accepted {
+                Ok(connection) => return Ok(Some(connection)),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Stops accepting. A thread waiting in [`accept`](Acceptor::accept) is
+    /// woken by connecting to the listener, which works the same everywhere.
+    pub(crate) fn close(&self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Ok(mut addr) = self.listener.local_addr() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+}
+
+impl fmt::Display for ListenTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "listen://{}:{}", self.addr.host, self.addr.port)
+    }
+}
+
+/// Connects to a Unix domain socket. On Windows this needs winsock's
+/// `AF_UNIX` support, found in Windows 10 1803 and later and in recent Wine.
+pub struct UnixTransport {
+    pub path: PathBuf,
+}
+
+impl KoreTransport for UnixTransport {
+    fn connect(&self) -> io::Result<Box<dyn KoreStream>> {
+        Ok(Box::new(UnixStream::connect(&self.path)?))
+    }
+}
+
+impl fmt::Display for UnixTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unix:{}", self.path.display())
+    }
+}
+
+impl KoreStream for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn KoreStream>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
@@ -0,0 +1,199 @@
+//! Windows backend: hooks winsock `recv`/`send` with Detours from `DllMain`.
+//!
+//! The DLL cannot be unloaded: it pins itself when loaded, so `FreeLibrary`
+//! leaves it in place and the relay runs until the process exits, when what
+//! is queued for Kore is flushed.
+
+use std::cell::Cell;
+use std::io;
+use std::sync::{Arc, Mutex};
+use detours_sys as detours;
+use lazy_static::lazy_static;
+use winapi::um::libloaderapi::{
+    GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS, GET_MODULE_HANDLE_EX_FLAG_PIN,
+};
+use winapi::um::winsock2::*;
+
+use crate::config::Config;
+use crate::hooks::SocketHooks;
+use crate::relay::{self, RelayHandle, FLUSH_TIMEOUT};
+use crate::state::{
+    capture_recv, route_send, take_client_data, wait_for_kore_space, RawSocket, SendRoute, NETWORK_STATE,
+};
+
+lazy_static! {
+    static ref RELAY: Mutex<Option<RelayHandle>> = Mutex::new(None);
+}
+
+thread_local! {
+    /// Set on the relay's threads, so their own socket calls (to Kore and to
+    /// observers) reach winsock untouched.
+    static BYPASS: Cell<bool> = const { Cell::new(false) };
+}
+
+fn bypassed() -> bool {
+    BYPASS.try_with(Cell::get).unwrap_or(true)
+}
+
+// Original WinAPI functions
+static mut ORIGINAL_RECV: Option<unsafe extern "system" fn(SOCKET, *mut i8, i32, i32) -> i32> = None;
+static mut ORIGINAL_SEND: Option<unsafe extern "system" fn(SOCKET, *const i8, i32, i32) -> i32> = None;
+
+/// Sends through the Detours trampoline of the original `send`.
+struct WinsockHooks;
+
+impl SocketHooks for WinsockHooks {
+    fn on_relay_thread(&self) {
+        BYPASS.with(|bypass| bypass.set(true));
+    }
+
+    fn send(&self, socket: RawSocket, data: &[u8]) -> io::Result<usize> {
+        let orig_send = match unsafe { ORIGINAL_SEND } {
+            Some(orig_send) => orig_send,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+
+        let ret = unsafe { orig_send(socket as SOCKET, data.as_ptr() as *const i8, data.len() as i32, 0) };
+        if ret == SOCKET_ERROR {
+            Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }))
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+// Hook implementations
+#[no_mangle]
+pub unsafe extern "system" fn hooked_recv(socket: SOCKET, buffer: *mut i8, len: i32, flags: i32) -> i32 {
+    if bypassed() {
+        return match ORIGINAL_RECV {
+            Some(orig_recv) => orig_recv(socket, buffer, len, flags),
+            None => SOCKET_ERROR,
+        };
+    }
+    println!("Called hooked_recv");
+
+    // Data injected by OpenKore takes priority over reading from the server,
+    // but only on the game's connection to it.
+    if len > 0 {
+        let mut state = NETWORK_STATE.lock().unwrap();
+        if state.ro_server() == Some(socket as RawSocket) && !state.client_data().is_empty() {
+            let out = std::slice::from_raw_parts_mut(buffer as *mut u8, len as usize);
+            return take_client_data(&mut state, out, flags & MSG_PEEK != 0) as i32;
+        }
+    }
+
+    let ret_len = if let Some(orig_recv) = ORIGINAL_RECV {
+        orig_recv(socket, buffer, len, flags)
+    } else {
+        return SOCKET_ERROR;
+    };
+
+    if ret_len != SOCKET_ERROR && ret_len > 0 {
+        let mut state = wait_for_kore_space(NETWORK_STATE.lock().unwrap());
+        let data = std::slice::from_raw_parts(buffer as *const u8, ret_len as usize);
+        capture_recv(&mut state, socket as RawSocket, data);
+    }
+
+    ret_len
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn hooked_send(socket: SOCKET, buffer: *const i8, len: i32, flags: i32) -> i32 {
+    let orig_send = match ORIGINAL_SEND {
+        Some(orig_send) => orig_send,
+        None => return SOCKET_ERROR,
+    };
+    if bypassed() {
+        return orig_send(socket, buffer, len, flags);
+    }
+    println!("Called hooked_send");
+
+    if len > 0 {
+        let mut state = wait_for_kore_space(NETWORK_STATE.lock().unwrap());
+        let data = std::slice::from_raw_parts(buffer as *const u8, len as usize);
+        if route_send(&mut state, socket as RawSocket, data) != SendRoute::Direct {
+            return len;
+        }
+    }
+
+    // Send directly to RO server
+    orig_send(socket, buffer, len, flags)
+}
+
+/// Lets a controlling tool skip the reconnect backoff, e.g. after restarting
+/// OpenKore.
+#[no_mangle]
+pub extern "system" fn netredirect_reconnect() {
+    if let Some(handle) = RELAY.lock().unwrap().as_ref() {
+        handle.reconnect_now();
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn DllMain(_hinst: *mut u8, reason: u32, _reserved: *mut u8) -> i32 {
+    match reason {
+        1 /* DLL_PROCESS_ATTACH */ => {
+            unsafe {
+                // Store original function pointers
+                ORIGINAL_RECV = Some(recv);
+                ORIGINAL_SEND = Some(send);
+                
+                // Set up hooks. Detours rewrites the stored pointers to point at
+                // trampolines, so calling them afterwards reaches the real functions.
+                detours::DetourTransactionBegin();
+                detours::DetourUpdateThread(winapi::um::processthreadsapi::GetCurrentThread() as _);
+                
+                detours::DetourAttach(std::ptr::addr_of_mut!(ORIGINAL_RECV) as *mut _, hooked_recv as *mut _);
+                detours::DetourAttach(std::ptr::addr_of_mut!(ORIGINAL_SEND) as *mut _, hooked_send as *mut _);
+                
+                detours::DetourTransactionCommit();
+            }
+            
+            // The relay's threads are never joined on Windows (see
+            // `RelayHandle::shutdown`), so keep the DLL mapped until the
+            // process exits rather than let FreeLibrary unload their code.
+            unsafe {
+                let mut module = std::ptr::null_mut();
+                GetModuleHandleExW(
+                    GET_MODULE_HANDLE_EX_FLAG_PIN | GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+                    DllMain as *const u16,
+                    &mut module,
+                );
+            }
+
+            // Start main thread
+            let handle = relay::spawn(Config::load(), NETWORK_STATE.clone(), Arc::new(WinsockHooks));
+            *RELAY.lock().unwrap() = Some(handle);
+        },
+        0 /* DLL_PROCESS_DETACH */ => {
+            // The DLL is pinned, so this is process exit: Windows has already
+            // terminated the relay thread, so flush from here instead.
+            let handle = RELAY.lock().ok().and_then(|mut relay| relay.take());
+            if let Some(handle) = handle {
+                // The hooks are still in place; the flush must not go
+                // through them.
+                WinsockHooks.on_relay_thread();
+                handle.flush_now(FLUSH_TIMEOUT);
+            }
+
+            unsafe {
+                // Remove hooks
+                detours::DetourTransactionBegin();
+                detours::DetourUpdateThread(winapi::um::processthreadsapi::GetCurrentThread() as _);
+                
+                let (orig_recv, orig_send) = (ORIGINAL_RECV, ORIGINAL_SEND);
+                if orig_recv.is_some() {
+                    detours::DetourDetach(std::ptr::addr_of_mut!(ORIGINAL_RECV) as *mut _, hooked_recv as *mut _);
+                }
+                if orig_send.is_some() {
+                    detours::DetourDetach(std::ptr::addr_of_mut!(ORIGINAL_SEND) as *mut _, hooked_send as *mut _);
+                }
+                
+                detours::DetourTransactionCommit();
+            }
+        },
+        _ => {}
+    }
+    1
+}
@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use netredirect_rust::backoff::Backoff;
+
+const SECOND: Duration = Duration::from_secs(1);
+
+#[test]
+fn doubles_up_to_the_ceiling() {
+    let mut backoff = Backoff::new(SECOND, 10 * SECOND, 0.0);
+    let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+}
+
+#[test]
+fn reset_starts_over() {
+    let mut backoff = Backoff::new(SECOND, 10 * SECOND, 0.0);
+    backoff.next_delay();
+    backoff.next_delay();
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), SECOND);
+}
+
+#[test]
+fn jitter_only_shortens_delays() {
+    let mut backoff = Backoff::new(SECOND, SECOND, 0.5);
+    let delays: Vec<Duration> = (0..100).map(|_| backoff.next_delay()).collect();
+
+    assert!(delays.iter().all(|d| *d >= SECOND / 2 && *d <= SECOND));
+    assert!(delays.windows(2).any(|w| w[0] != w[1]));
+}
+
+#[test]
+fn survives_many_failures() {
+    let mut backoff = Backoff::new(SECOND, 60 * SECOND, 0.0);
+    for _ in 0..100 {
+        assert!(backoff.next_delay() <= 60 * SECOND);
+    }
+}
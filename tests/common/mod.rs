@@ -0,0 +1,102 @@
+//! Stand-ins and helpers shared by the integration tests.
+
+// Each test crate uses only some of these.
+#![allow(dead_code)]
+
+use std::io::{self, Read};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use netredirect_rust::frame::FrameDecoder;
+use netredirect_rust::relay::{self, RelayHandle};
+use netredirect_rust::state::RawSocket;
+use netredirect_rust::{Config, NetworkState, SocketHooks, XKoreFrame};
+
+/// Hooks for a game that has no RO server connection.
+pub struct NoServer;
+
+impl SocketHooks for NoServer {
+    fn send(&self, _socket: RawSocket, _data: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// Records what the relay sends to the RO server.
+#[derive(Default)]
+pub struct RecordingHooks {
+    pub sent: Mutex<Vec<(RawSocket, Vec<u8>)>>,
+}
+
+impl RecordingHooks {
+    /// Everything sent to the RO server so far, in order.
+    pub fn server_data(&self) -> Vec<u8> {
+        self.sent.lock().unwrap().iter().flat_map(|(_, data)| data.clone()).collect()
+    }
+}
+
+impl SocketHooks for RecordingHooks {
+    fn send(&self, socket: RawSocket, data: &[u8]) -> io::Result<usize> {
+        self.sent.lock().unwrap().push((socket, data.to_vec()));
+        Ok(data.len())
+    }
+}
+
+pub fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Starts a relay with the config `configure` sets up and accepts its
+/// connection as Kore.
+pub fn start_relay(
+    hooks: Arc<dyn SocketHooks>,
+    configure: impl FnOnce(&mut Config),
+) -> (TcpStream, Arc<Mutex<NetworkState>>, RelayHandle) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = Config { kore_port: listener.local_addr().unwrap().port(), ..Config::default() };
+    configure(&mut config);
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), hooks);
+
+    let (kore, _) = listener.accept().unwrap();
+    kore.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    (kore, state, relay)
+}
+
+/// Reads the next frame other than a keep-alive, or `None` once the relay
+/// has closed the connection.
+pub fn read_frame(kore: &mut impl Read, decoder: &mut FrameDecoder) -> Option<XKoreFrame> {
+    let mut buf = [0u8; 1024];
+    loop {
+        match decoder.next_frame() {
+            Some(XKoreFrame::KeepAlive) => continue,
+            Some(frame) => return Some(frame),
+            None => match kore.read(&mut buf).unwrap() {
+                0 => return None,
+                n => decoder.extend(&buf[..n]),
+            },
+        }
+    }
+}
+
+/// Connects to a relay that is about to listen on `port`.
+pub fn connect_when_listening(port: u16) -> TcpStream {
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                return stream;
+            }
+            Err(_) => thread::sleep(Duration::from_millis(5)),
+        }
+    }
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
@@ -0,0 +1,245 @@
+use std::time::Duration;
+
+use netredirect_rust::config::{
+    AddressFamily, ConfigError, Endpoint, FailbackPolicy, OverflowPolicy, TransportKind, DEFAULT_KORE_PORT,
+    DEFAULT_PING_TIMEOUT,
+};
+use netredirect_rust::Config;
+
+fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn defaults_to_local_kore() {
+    let config = Config::default();
+    assert_eq!(config.kore_host, "127.0.0.1");
+    assert_eq!(config.kore_port, DEFAULT_KORE_PORT);
+    assert_eq!(config.kore_family, AddressFamily::Any);
+}
+
+#[test]
+fn reads_file_settings() {
+    let mut config = Config::default();
+    let errors = config.apply_file(
+        "# second bot\n\
+         kore_host = 10.0.0.2\n\
+         \n\
+         ; comment\n\
+         kore_port=2351\n\
+         kore_family = ipv4\n",
+    );
+
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.kore_host, "10.0.0.2");
+    assert_eq!(config.kore_port, 2351);
+    assert_eq!(config.kore_family, AddressFamily::V4);
+}
+
+#[test]
+fn invalid_lines_keep_defaults() {
+    let mut config = Config::default();
+    let errors = config.apply_file("kore_port = 70000\nnonsense\nkore_colour = red\nkore_port = 2352\n");
+
+    assert_eq!(errors.len(), 3);
+    assert!(matches!(errors[0], ConfigError::InvalidValue { .. }));
+    assert!(matches!(errors[1], ConfigError::Syntax { line: 2 }));
+    assert!(matches!(errors[2], ConfigError::UnknownKey(_)));
+    assert_eq!(config.kore_port, 2352);
+}
+
+#[test]
+fn features_without_hello_are_reported() {
+    let mut config = Config::default();
+    config.apply_file("kore_timestamps = on\nkore_compression = on\n");
+
+    let errors = config.check();
+    assert_eq!(errors.len(), 2);
+    assert!(matches!(&errors[0], ConfigError::Unused { key, needs } if key == "kore_timestamps" && needs == "kore_hello"));
+    assert!(matches!(&errors[1], ConfigError::Unused { key, .. } if key == "kore_compression"));
+
+    config.apply_file("kore_hello = on\n");
+    assert!(config.check().is_empty());
+}
+
+#[test]
+fn environment_overrides_file() {
+    let mut config = Config::default();
+    config.apply_file("kore_port = 2351\n");
+    let errors = config.apply_env(vars(&[
+        ("NETREDIRECT_KORE_PORT", "2400"),
+        ("NETREDIRECT_KORE_FAMILY", "v6"),
+        ("NETREDIRECT_CONFIG", "ignored.ini"),
+        ("PATH", "/usr/bin"),
+    ]));
+
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.kore_port, 2400);
+    assert_eq!(config.kore_family, AddressFamily::V6);
+}
+
+#[test]
+fn resolves_only_requested_family() {
+    let mut config = Config::default();
+    assert!(config.kore_addrs().unwrap().iter().all(|addr| addr.is_ipv4()));
+
+    config.kore_family = AddressFamily::V6;
+    assert!(config.kore_addrs().is_err());
+}
+
+#[test]
+fn reads_keep_alive_timing() {
+    let mut config = Config::default();
+    let errors = config.apply_file("ping_interval = 1000\nping_timeout = 0\n");
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(config.ping_interval, Duration::from_millis(1000));
+    assert_eq!(config.ping_timeout, DEFAULT_PING_TIMEOUT);
+}
+
+#[test]
+fn reads_reconnect_backoff() {
+    let mut config = Config::default();
+    let errors = config.apply_file("reconnect_delay = 500\nreconnect_max_delay = 20000\nreconnect_jitter = 150\nreconnect_jitter = 25\n");
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(config.reconnect_delay, Duration::from_millis(500));
+    assert_eq!(config.reconnect_max_delay, Duration::from_secs(20));
+    assert_eq!(config.reconnect_jitter, 0.25);
+}
+
+#[test]
+fn reads_queue_overflow_policy() {
+    let mut config = Config::default();
+    assert_eq!(config.kore_overflow, OverflowPolicy::Disconnect);
+
+    let errors = config.apply_file("kore_queue_limit = 65536\nkore_overflow = Drop_Oldest\nkore_overflow = spill\nkore_queue_limit = 0\n");
+    assert_eq!(errors.len(), 2);
+    assert_eq!(config.kore_queue_limit, 65536);
+    assert_eq!(config.kore_overflow, OverflowPolicy::DropOldest);
+}
+
+#[test]
+fn selects_unix_transport() {
+    let mut config = Config::default();
+    assert_eq!(config.kore_transport, TransportKind::Tcp);
+
+    let errors = config.apply_env(vars(&[
+        ("NETREDIRECT_KORE_TRANSPORT", "unix"),
+        ("NETREDIRECT_KORE_PATH", "/run/openkore/bot1.sock"),
+    ]));
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.kore_transport, TransportKind::Unix);
+    assert_eq!(config.kore_path, std::path::Path::new("/run/openkore/bot1.sock"));
+
+    assert_eq!(config.apply_file("kore_transport = pipe\nkore_path =\n").len(), 2);
+}
+
+#[test]
+fn selects_listen_transport() {
+    let mut config = Config::default();
+    let errors = config.apply_file("kore_transport = Listen\nkore_host = 0.0.0.0\nkore_port = 2351\n");
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.kore_transport, TransportKind::Listen);
+    assert_eq!((config.kore_host.as_str(), config.kore_port), ("0.0.0.0", 2351));
+}
+
+#[test]
+fn reads_failover_endpoints() {
+    let mut config = Config::default();
+    assert!(config.kore_failover.is_empty());
+    assert_eq!(config.kore_failback, FailbackPolicy::Reconnect);
+
+    let errors = config.apply_file(
+        "kore_failover = 10.0.0.6:2350, tcp://[::1]:2351 ,unix:/run/kore.sock\nkore_failback = 300000\n",
+    );
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(
+        config.kore_failover,
+        [
+            Endpoint::Tcp { host: "10.0.0.6".to_string(), port: 2350 },
+            Endpoint::Tcp { host: "::1".to_string(), port: 2351 },
+            Endpoint::Unix(std::path::PathBuf::from("/run/kore.sock")),
+        ]
+    );
+    assert_eq!(config.kore_failback, FailbackPolicy::After(Duration::from_secs(300)));
+
+    let errors = config.apply_file("kore_failover = standby\nkore_failback = Never\nkore_failback = soon\n");
+    assert_eq!(errors.len(), 2);
+    assert_eq!(config.kore_failover.len(), 3);
+    assert_eq!(config.kore_failback, FailbackPolicy::Never);
+}
+
+#[test]
+fn reads_observer_settings() {
+    let mut config = Config::default();
+    assert_eq!(config.observer_port, None);
+
+    let errors = config.apply_file("observer_host = 0.0.0.0\nobserver_port = 2352\nobserver_queue_limit = 65536\nobserver_queue_limit = 0\n");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(config.observer_host, "0.0.0.0");
+    assert_eq!(config.observer_port, Some(2352));
+    assert_eq!(config.observer_queue_limit, 65536);
+}
+
+#[test]
+fn reads_tls_settings() {
+    let mut config = Config::default();
+    assert!(!config.kore_tls);
+
+    let errors = config.apply_file("kore_tls = on\nkore_tls_cert = kore.pem\nkore_tls = maybe\n");
+    assert_eq!(errors.len(), 1);
+    assert!(config.kore_tls);
+    assert_eq!(config.kore_tls_cert, Some(std::path::PathBuf::from("kore.pem")));
+}
+
+#[test]
+fn reads_shared_secret() {
+    let mut config = Config::default();
+    assert_eq!(config.kore_secret, None);
+
+    let errors = config.apply_env(vars(&[("NETREDIRECT_KORE_SECRET", "s3cret")]));
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(config.kore_secret.as_deref(), Some("s3cret"));
+}
+
+#[test]
+fn reads_hello_settings() {
+    let mut config = Config::default();
+    assert!(!config.kore_hello);
+
+    assert!(!config.kore_timestamps);
+
+    let errors = config.apply_file("kore_hello = on\nhello_timeout = 250\nkore_timestamps = yes\n");
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(config.kore_hello);
+    assert!(config.kore_timestamps);
+    assert_eq!(config.hello_timeout, Duration::from_millis(250));
+}
+
+#[test]
+fn reads_compression_setting() {
+    let mut config = Config::default();
+    assert!(!config.kore_compression);
+    assert!(config.apply_file("kore_compression = 1\n").is_empty());
+    assert!(config.kore_compression);
+}
+
+#[cfg(unix)]
+#[test]
+fn skips_non_unicode_environment() {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut config = Config::default();
+    let errors = config.apply_env([
+        (OsString::from_vec(b"LANG\xff".to_vec()), OsString::from("C")),
+        (OsString::from("TERM"), OsString::from_vec(b"x\xfe".to_vec())),
+        (OsString::from("NETREDIRECT_KORE_HOST"), OsString::from_vec(b"kore\xff".to_vec())),
+        (OsString::from("NETREDIRECT_KORE_PORT"), OsString::from("2351")),
+    ]);
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(&errors[0], ConfigError::InvalidValue { key, .. } if key == "kore_host"));
+    assert_eq!(config.kore_host, netredirect_rust::config::DEFAULT_KORE_HOST);
+    assert_eq!(config.kore_port, 2351);
+}
@@ -0,0 +1,61 @@
+use std::thread;
+use std::time::Duration;
+
+use netredirect_rust::config::FailbackPolicy;
+use netredirect_rust::failover::Failover;
+
+#[test]
+fn tries_every_endpoint_before_backing_off() {
+    let mut failover = Failover::new(3, FailbackPolicy::Reconnect);
+    assert_eq!(failover.current(), 0);
+    assert!(!failover.attempt_failed());
+    assert_eq!(failover.current(), 1);
+    assert!(!failover.attempt_failed());
+    assert_eq!(failover.current(), 2);
+    assert!(failover.attempt_failed());
+    assert_eq!(failover.current(), 0);
+}
+
+#[test]
+fn lost_standby_returns_to_primary() {
+    let mut failover = Failover::new(3, FailbackPolicy::Reconnect);
+    assert!(failover.link_lost());
+    assert_eq!(failover.current(), 1);
+    failover.connected();
+    assert!(failover.link_lost());
+    assert_eq!(failover.current(), 0);
+
+    let mut never = Failover::new(3, FailbackPolicy::Never);
+    never.attempt_failed();
+    never.connected();
+    assert!(never.link_lost());
+    assert_eq!(never.current(), 2);
+    assert!(never.link_lost());
+    assert_eq!(never.current(), 0);
+}
+
+#[test]
+fn single_endpoint_backs_off_when_lost() {
+    let mut failover = Failover::new(1, FailbackPolicy::Reconnect);
+    assert!(!failover.link_lost());
+    assert!(failover.attempt_failed());
+    assert_eq!(failover.current(), 0);
+}
+
+#[test]
+fn fails_back_after_the_configured_time() {
+    let mut failover = Failover::new(2, FailbackPolicy::After(Duration::from_millis(20)));
+    failover.connected();
+    thread::sleep(Duration::from_millis(30));
+    // The primary is never failed back from.
+    assert!(!failover.should_fail_back());
+
+    failover.attempt_failed();
+    failover.connected();
+    assert!(!failover.should_fail_back());
+    thread::sleep(Duration::from_millis(30));
+    assert!(failover.should_fail_back());
+    failover.fail_back();
+    assert_eq!(failover.current(), 0);
+    assert!(!failover.should_fail_back());
+}
@@ -0,0 +1,220 @@
+use netredirect_rust::compress;
+use netredirect_rust::frame::{FrameDecoder, Stamp, HEADER_LEN, MAX_PAYLOAD};
+use netredirect_rust::XKoreFrame;
+
+#[test]
+fn round_trips_every_frame_type() {
+    let frames = [
+        XKoreFrame::Received(vec![0x73, 0x00, 0x01, 0x02]),
+        XKoreFrame::Sent(vec![0x7d, 0x00]),
+        XKoreFrame::KeepAlive,
+        XKoreFrame::Auth(vec![0xaa; 32]),
+        XKoreFrame::Hello(vec![1, 0, 0, 0, 0, 0]),
+        XKoreFrame::Unknown { kind: b'Q', payload: vec![1, 2, 3] },
+        XKoreFrame::Received(Vec::new()),
+    ];
+
+    for frame in frames {
+        let encoded = frame.encode();
+        assert_eq!(encoded[0], frame.kind());
+        assert_eq!(XKoreFrame::decode(&encoded), Some((frame, encoded.len())));
+    }
+}
+
+#[test]
+fn encodes_length_little_endian() {
+    let encoded = XKoreFrame::Sent(vec![0; 0x0102]).encode();
+    assert_eq!(&encoded[..3], &[b'S', 0x02, 0x01]);
+}
+
+#[test]
+fn decode_waits_for_complete_frame() {
+    let encoded = XKoreFrame::Received(vec![1, 2, 3, 4]).encode();
+    for end in 0..encoded.len() {
+        assert_eq!(XKoreFrame::decode(&encoded[..end]), None);
+    }
+}
+
+#[test]
+fn keep_alive_ignores_payload() {
+    assert_eq!(XKoreFrame::decode(&[b'K', 2, 0, 9, 9]), Some((XKoreFrame::KeepAlive, 5)));
+}
+
+#[test]
+fn decoder_reassembles_partial_reads() {
+    let encoded = XKoreFrame::Sent(vec![9; 300]).encode();
+    let mut decoder = FrameDecoder::new();
+
+    for chunk in encoded.chunks(7) {
+        assert_eq!(decoder.next_frame(), None);
+        decoder.extend(chunk);
+    }
+    assert_eq!(decoder.next_frame(), Some(XKoreFrame::Sent(vec![9; 300])));
+    assert_eq!(decoder.pending(), 0);
+}
+
+#[test]
+fn decoder_splits_coalesced_frames() {
+    let mut stream = Vec::new();
+    XKoreFrame::Received(vec![1]).encode_into(&mut stream);
+    XKoreFrame::KeepAlive.encode_into(&mut stream);
+    XKoreFrame::Unknown { kind: b'Z', payload: vec![5, 5] }.encode_into(&mut stream);
+    XKoreFrame::Sent(vec![2, 3]).encode_into(&mut stream);
+    stream.push(b'R');
+
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(&stream);
+    assert_eq!(decoder.next_frame(), Some(XKoreFrame::Received(vec![1])));
+    assert_eq!(decoder.next_frame(), Some(XKoreFrame::KeepAlive));
+    assert_eq!(decoder.next_frame(), Some(XKoreFrame::Unknown { kind: b'Z', payload: vec![5, 5] }));
+    assert_eq!(decoder.next_frame(), Some(XKoreFrame::Sent(vec![2, 3])));
+    assert_eq!(decoder.next_frame(), None);
+    assert_eq!(decoder.pending(), 1);
+}
+
+#[test]
+fn largest_payload_fits_one_frame() {
+    let frame = XKoreFrame::Received(vec![7; MAX_PAYLOAD]);
+    let encoded = frame.encode();
+
+    assert_eq!(encoded.len(), HEADER_LEN + MAX_PAYLOAD);
+    assert_eq!(frame.encoded_len(), encoded.len());
+    assert_eq!(&encoded[..3], &[b'R', 0xff, 0xff]);
+    assert_eq!(XKoreFrame::decode(&encoded), Some((frame, encoded.len())));
+}
+
+#[test]
+fn oversized_payload_is_split() {
+    for len in [MAX_PAYLOAD + 1, 2 * MAX_PAYLOAD, 2 * MAX_PAYLOAD + 1, 200_000] {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let frame = XKoreFrame::Sent(payload.clone());
+        let encoded = frame.encode();
+        assert_eq!(frame.encoded_len(), encoded.len());
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&encoded);
+        let mut joined = Vec::new();
+        while let Some(part) = decoder.next_frame() {
+            assert!(part.payload().len() <= MAX_PAYLOAD);
+            match part {
+                XKoreFrame::Sent(data) => joined.extend(data),
+                other => panic!("unexpected frame {:?}", other),
+            }
+        }
+        assert_eq!(joined, payload);
+        assert_eq!(decoder.pending(), 0);
+    }
+}
+
+#[test]
+fn peeks_only_complete_frames() {
+    let encoded = XKoreFrame::Hello(vec![1, 0, 0, 0, 0, 0]).encode();
+    let mut decoder = FrameDecoder::new();
+
+    decoder.extend(&encoded[..5]);
+    assert_eq!(decoder.peek_kind(), None);
+    decoder.extend(&encoded[5..]);
+    assert_eq!(decoder.peek_kind(), Some(b'H'));
+    assert_eq!(decoder.pending(), encoded.len());
+}
+
+#[test]
+fn stamp_round_trips() {
+    let stamp = Stamp { direction: b'S', sequence: 0x01020304, micros: 0x1122334455667788 };
+    let frame = stamp.to_frame();
+
+    assert_eq!(frame.kind(), b'T');
+    assert_eq!(frame.payload().len(), Stamp::LEN);
+    assert_eq!(&frame.payload()[..5], &[b'S', 0x04, 0x03, 0x02, 0x01]);
+    assert_eq!(Stamp::parse(frame.payload()), Some(stamp));
+    assert_eq!(Stamp::parse(&frame.payload()[..12]), None);
+}
+
+/// Frames that look a bit like game traffic: small, repetitive packets.
+fn chatty_stream(frames: usize) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for i in 0..frames {
+        let packet = [0x87, 0x00, (i % 7) as u8, 0x12, 0x34, 0x56, 0x00, 0x00, 0x64, 0x00, 0x88, 0x00];
+        XKoreFrame::Received(packet.to_vec()).encode_into(&mut stream);
+    }
+    stream
+}
+
+#[test]
+fn compressed_stream_decodes_to_the_original() {
+    let stream = chatty_stream(20_000);
+    let mut packed = Vec::new();
+    compress::compress_into(&stream, &mut packed);
+    assert!(packed.len() < stream.len() / 4, "{} -> {}", stream.len(), packed.len());
+
+    // Chunk boundaries fall inside frames; the decoder has to splice them.
+    let mut decoder = FrameDecoder::new();
+    decoder.accept_compressed(true);
+    let mut decoded = Vec::new();
+    for part in packed.chunks(1000) {
+        decoder.extend(part);
+        while let Some(frame) = decoder.next_frame() {
+            frame.encode_into(&mut decoded);
+        }
+    }
+    assert_eq!(decoded, stream);
+}
+
+#[test]
+fn incompressible_data_is_sent_as_is() {
+    let mut seed = 0x2545f491u32;
+    let noise: Vec<u8> = (0..150_000)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        })
+        .collect();
+    let stream = XKoreFrame::Received(noise).encode();
+
+    let mut packed = Vec::new();
+    compress::compress_into(&stream, &mut packed);
+    assert_eq!(packed, stream);
+}
+
+#[test]
+fn corrupt_compressed_frame_is_reported() {
+    let mut decoder = FrameDecoder::new();
+    decoder.accept_compressed(true);
+    decoder.extend(&XKoreFrame::Compressed(vec![5, 0, 0, 0, 0xff]).encode());
+    // Claims to unpack to far more than a chunk.
+    let bomb = XKoreFrame::Compressed(vec![0xff, 0xff, 0xff, 0x7f, 0x1f, 0x00]).encode();
+    decoder.extend(&bomb);
+    decoder.extend(&XKoreFrame::KeepAlive.encode());
+
+    for _ in 0..2 {
+        assert!(matches!(decoder.next_frame(), Some(XKoreFrame::Unknown { kind: b'Z', .. })));
+    }
+    assert_eq!(decoder.next_frame(), Some(XKoreFrame::KeepAlive));
+}
+
+#[test]
+fn compressed_frames_need_agreement() {
+    let mut packed = Vec::new();
+    compress::compress_into(&chatty_stream(100), &mut packed);
+    assert_eq!(packed[0], b'Z');
+
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(&packed);
+    assert!(matches!(decoder.next_frame(), Some(XKoreFrame::Unknown { kind: b'Z', .. })));
+}
+
+#[test]
+fn nested_compressed_frame_is_reported() {
+    let mut inner = Vec::new();
+    compress::compress_into(&chatty_stream(100), &mut inner);
+    let outer = XKoreFrame::Compressed(lz4_flex::compress_prepend_size(&inner)).encode();
+
+    let mut decoder = FrameDecoder::new();
+    decoder.accept_compressed(true);
+    decoder.extend(&outer);
+    decoder.extend(&XKoreFrame::KeepAlive.encode());
+    assert!(matches!(decoder.next_frame(), Some(XKoreFrame::Unknown { kind: b'Z', .. })));
+    assert_eq!(decoder.next_frame(), Some(XKoreFrame::KeepAlive));
+}
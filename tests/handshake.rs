@@ -0,0 +1,331 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{read_frame, start_relay, wait_until, NoServer};
+use netredirect_rust::config::OverflowPolicy;
+use netredirect_rust::frame::{FrameDecoder, Stamp};
+use netredirect_rust::handshake::{self, Features, Hello, CHALLENGE_LEN, PROTOCOL_VERSION};
+use netredirect_rust::relay::RelayHandle;
+use netredirect_rust::state::{capture_recv, route_send, QueueDrops, SendRoute};
+use netredirect_rust::{Config, LinkState, NetworkState, XKoreFrame};
+
+const SECRET: &[u8] = b"correct horse battery staple";
+
+/// Starts a relay that offers a hello, with the config `configure` sets up,
+/// and accepts its connection.
+fn start(configure: impl FnOnce(&mut Config)) -> (TcpStream, Arc<Mutex<NetworkState>>, RelayHandle) {
+    start_relay(Arc::new(NoServer), |config| {
+        config.kore_hello = true;
+        configure(config);
+    })
+}
+
+/// Authenticates with `SECRET`, without a hello.
+fn with_secret(ping_timeout: Duration) -> impl FnOnce(&mut Config) {
+    move |config| {
+        config.kore_secret = Some(String::from_utf8(SECRET.to_vec()).unwrap());
+        config.ping_timeout = ping_timeout;
+        config.kore_hello = false;
+    }
+}
+
+#[test]
+fn answer_is_hmac_sha256() {
+    // RFC 4231, test case 2
+    let answer = handshake::respond(b"Jefe", b"what do ya want for nothing?");
+    let expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+    let hex: String = answer.iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(hex, expected);
+}
+
+#[test]
+fn authenticated_kore_gets_traffic() {
+    let (mut kore, state, relay) = start(with_secret(Duration::from_secs(10)));
+    let mut decoder = FrameDecoder::new();
+
+    let challenge = match read_frame(&mut kore, &mut decoder) {
+        Some(XKoreFrame::Auth(challenge)) => challenge,
+        other => panic!("expected a challenge, got {:?}", other),
+    };
+    assert_eq!(challenge.len(), CHALLENGE_LEN);
+    assert_eq!(state.lock().unwrap().link_state(), LinkState::Connecting);
+
+    // An injected packet sent right behind the answer is not lost.
+    let mut reply = XKoreFrame::Auth(handshake::respond(SECRET, &challenge)).encode();
+    XKoreFrame::Received(b"\x87\x00".to_vec()).encode_into(&mut reply);
+    kore.write_all(&reply).unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+    wait_until("client data", || state.lock().unwrap().client_data() == b"\x87\x00");
+
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn wrong_answer_is_refused() {
+    let (mut kore, state, relay) = start(with_secret(Duration::from_secs(10)));
+    let mut decoder = FrameDecoder::new();
+
+    let challenge = match read_frame(&mut kore, &mut decoder) {
+        Some(XKoreFrame::Auth(challenge)) => challenge,
+        other => panic!("expected a challenge, got {:?}", other),
+    };
+    let answer = handshake::respond(b"guessed secret", &challenge);
+    kore.write_all(&XKoreFrame::Auth(answer).encode()).unwrap();
+
+    // The relay hangs up without sending anything else.
+    assert_eq!(read_frame(&mut kore, &mut decoder), None);
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_ne!(state.lock().unwrap().link_state(), LinkState::Connected);
+    assert!(state.lock().unwrap().kore_frames().is_empty());
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn silent_peer_is_refused() {
+    let (mut kore, state, relay) = start(with_secret(Duration::from_millis(200)));
+    let mut decoder = FrameDecoder::new();
+
+    assert!(matches!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Auth(_))));
+    assert_eq!(read_frame(&mut kore, &mut decoder), None);
+    assert_ne!(state.lock().unwrap().link_state(), LinkState::Connected);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn dribbling_peer_is_refused_in_time() {
+    let (mut kore, state, relay) = start(with_secret(Duration::from_millis(300)));
+    let mut decoder = FrameDecoder::new();
+    let challenge = match read_frame(&mut kore, &mut decoder) {
+        Some(XKoreFrame::Auth(challenge)) => challenge,
+        other => panic!("expected a challenge, got {:?}", other),
+    };
+    let started = Instant::now();
+
+    // Each byte arrives well within the timeout, the whole answer does not.
+    let mut dribble = kore.try_clone().unwrap();
+    let answer = XKoreFrame::Auth(handshake::respond(SECRET, &challenge)).encode();
+    let dribbler = thread::spawn(move || {
+        for byte in answer {
+            if dribble.write_all(&[byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(150));
+        }
+    });
+    assert_eq!(read_frame(&mut kore, &mut decoder), None);
+    assert!(started.elapsed() < Duration::from_secs(1), "refused after {:?}", started.elapsed());
+    assert_ne!(state.lock().unwrap().link_state(), LinkState::Connected);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    dribbler.join().unwrap();
+}
+
+#[test]
+fn hello_round_trips() {
+    // Bits this build does not know survive too.
+    let hello = Hello { version: 3, features: Features::TIMESTAMPS | Features(1 << 3) };
+    match hello.to_frame() {
+        XKoreFrame::Hello(payload) => {
+            assert_eq!(payload, [3, 0, 0x0a, 0, 0, 0]);
+            assert_eq!(Hello::parse(&payload), Some(hello));
+        }
+        other => panic!("expected a hello, got {:?}", other),
+    }
+    // Later versions may append fields, but the header has to be complete.
+    assert_eq!(Hello::parse(&[1, 0, 0, 0, 0, 0, 9, 9]), Some(Hello { version: 1, features: Features::NONE }));
+    assert_eq!(Hello::parse(&[1, 0, 0]), None);
+}
+
+#[test]
+fn agrees_on_common_features() {
+    let (mut kore, state, relay) = start(|_| {});
+    let mut decoder = FrameDecoder::new();
+
+    let offer = match read_frame(&mut kore, &mut decoder) {
+        Some(XKoreFrame::Hello(payload)) => Hello::parse(&payload).unwrap(),
+        other => panic!("expected a hello, got {:?}", other),
+    };
+    assert_eq!(offer, Hello { version: PROTOCOL_VERSION, features: Features::NONE });
+
+    // A newer Kore that knows every feature talks at our level.
+    let answer = Hello { version: PROTOCOL_VERSION + 1, features: Features(u32::MAX) };
+    kore.write_all(&answer.to_frame().encode()).unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+    assert_eq!(state.lock().unwrap().kore_protocol(), Some(offer));
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn silent_kore_gets_legacy_protocol() {
+    let (mut kore, state, relay) = start(|config| config.hello_timeout = Duration::from_millis(100));
+    let mut decoder = FrameDecoder::new();
+
+    assert!(matches!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Hello(_))));
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+    assert_eq!(state.lock().unwrap().kore_protocol(), None);
+
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn legacy_kore_traffic_is_not_lost() {
+    // A long timeout shows the relay does not wait it out once Kore has
+    // answered with something other than a hello.
+    let (mut kore, state, relay) = start(|config| config.hello_timeout = Duration::from_secs(30));
+    let mut decoder = FrameDecoder::new();
+
+    assert!(matches!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Hello(_))));
+    kore.write_all(&XKoreFrame::Received(b"\x87\x00".to_vec()).encode()).unwrap();
+    wait_until("client data", || state.lock().unwrap().client_data() == b"\x87\x00");
+    assert_eq!(state.lock().unwrap().kore_protocol(), None);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+/// Answers the relay's hello with `features` and waits for the link.
+fn answer_hello(
+    kore: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    state: &Mutex<NetworkState>,
+    features: Features,
+) -> Hello {
+    let offer = match read_frame(kore, decoder) {
+        Some(XKoreFrame::Hello(payload)) => Hello::parse(&payload).unwrap(),
+        other => panic!("expected a hello, got {:?}", other),
+    };
+    kore.write_all(&Hello { version: PROTOCOL_VERSION, features }.to_frame().encode()).unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+    offer
+}
+
+fn read_stamp(kore: &mut TcpStream, decoder: &mut FrameDecoder) -> Stamp {
+    match read_frame(kore, decoder) {
+        Some(XKoreFrame::Timestamp(payload)) => Stamp::parse(&payload).unwrap(),
+        other => panic!("expected a timestamp, got {:?}", other),
+    }
+}
+
+#[test]
+fn stamps_captured_traffic() {
+    let (mut kore, state, relay) = start(|config| config.kore_timestamps = true);
+    let mut decoder = FrameDecoder::new();
+    let offer = answer_hello(&mut kore, &mut decoder, &state, Features::TIMESTAMPS | Features::COMPRESSION);
+    assert_eq!(offer.features, Features::TIMESTAMPS);
+
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x7d\x00"), SendRoute::Kore);
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x87\x00");
+
+    let mut last_micros = 0;
+    let expected = [(b'R', 0, b"\x73\x00"), (b'S', 0, b"\x7d\x00"), (b'R', 1, b"\x87\x00")];
+    for (direction, sequence, payload) in expected {
+        let stamp = read_stamp(&mut kore, &mut decoder);
+        assert_eq!((stamp.direction, stamp.sequence), (direction, sequence));
+        assert!(stamp.micros >= last_micros);
+        last_micros = stamp.micros;
+
+        let frame = read_frame(&mut kore, &mut decoder).unwrap();
+        assert_eq!((frame.kind(), frame.payload()), (direction, &payload[..]));
+    }
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn no_stamps_unless_agreed() {
+    let (mut kore, state, relay) = start(|config| config.kore_timestamps = true);
+    let mut decoder = FrameDecoder::new();
+    answer_hello(&mut kore, &mut decoder, &state, Features::NONE);
+
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn dropped_frames_take_their_stamps() {
+    let (mut kore, state, relay) = start(|config| {
+        config.kore_timestamps = true;
+        // Room for two stamped 2-byte frames
+        config.kore_queue_limit = 2 * (3 + Stamp::LEN + 3 + 2);
+        config.kore_overflow = OverflowPolicy::DropOldest;
+    });
+    let mut decoder = FrameDecoder::new();
+    answer_hello(&mut kore, &mut decoder, &state, Features::TIMESTAMPS);
+
+    let mut locked = state.lock().unwrap();
+    for packet in [b"\x01\x00", b"\x02\x00", b"\x03\x00"] {
+        capture_recv(&mut locked, 5, packet);
+    }
+    assert_eq!(locked.kore_dropped(), QueueDrops { frames: 1, bytes: 2 });
+    drop(locked);
+
+    // The gap in the sequence shows what was dropped.
+    for (sequence, packet) in [(1, b"\x02\x00"), (2, b"\x03\x00")] {
+        assert_eq!(read_stamp(&mut kore, &mut decoder).sequence, sequence);
+        assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Received(packet.to_vec())));
+    }
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn compresses_when_agreed() {
+    let (mut kore, state, relay) = start(|config| config.kore_compression = true);
+    let mut decoder = FrameDecoder::new();
+    let offer = answer_hello(&mut kore, &mut decoder, &state, Features::COMPRESSION);
+    assert_eq!(offer.features, Features::COMPRESSION);
+    decoder.accept_compressed(true);
+
+    // Queue a burst in one go so it goes out in one compressed write.
+    let packet = b"\x87\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a".repeat(100);
+    let mut locked = state.lock().unwrap();
+    for _ in 0..50 {
+        capture_recv(&mut locked, 5, &packet);
+    }
+    drop(locked);
+
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut received = Vec::new();
+    while received.len() < 50 * packet.len() {
+        let n = kore.read(&mut buf).unwrap();
+        assert!(n > 0, "relay closed the connection");
+        raw.extend_from_slice(&buf[..n]);
+        decoder.extend(&buf[..n]);
+        while let Some(frame) = decoder.next_frame() {
+            match frame {
+                XKoreFrame::Received(data) => received.extend(data),
+                XKoreFrame::KeepAlive => {}
+                other => panic!("unexpected frame {:?}", other),
+            }
+        }
+    }
+    assert_eq!(received, packet.repeat(50));
+    assert_eq!(raw[0], b'Z');
+    assert!(raw.len() < received.len() / 4);
+
+    // Compressed frames from Kore are unpacked as well.
+    let mut injected = Vec::new();
+    netredirect_rust::compress::compress_into(&XKoreFrame::Received(packet.clone()).encode(), &mut injected);
+    assert_eq!(injected[0], b'Z');
+    kore.write_all(&injected).unwrap();
+    wait_until("client data", || state.lock().unwrap().client_data() == &packet[..]);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
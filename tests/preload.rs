@@ -0,0 +1,132 @@
+//! Runs the preload backend in a child process: the stand-in client from
+//! `examples/preload_client.rs` with the built library in `LD_PRELOAD`,
+//! talking to stand-in RO and Kore servers.
+#![cfg(all(target_os = "linux", feature = "preload"))]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use common::{read_frame, wait_until};
+use netredirect_rust::frame::FrameDecoder;
+use netredirect_rust::XKoreFrame;
+
+struct Client {
+    process: Child,
+    commands: ChildStdin,
+    results: Receiver<String>,
+}
+
+impl Client {
+    /// Starts the client with the library preloaded, relaying to Kore on
+    /// `kore_port`.
+    fn start(kore_port: u16) -> Client {
+        // Integration tests run from target/<profile>/deps, where `cargo
+        // test` also builds the library; the examples go beside it.
+        let deps = std::env::current_exe().unwrap().parent().unwrap().to_path_buf();
+        let client = deps.join("../examples/preload_client");
+        assert!(client.exists(), "{} is missing; it is built by `cargo test`", client.display());
+
+        let mut process = Command::new(client)
+            .env("LD_PRELOAD", deps.join("libnetredirect_rust.so"))
+            .env("NETREDIRECT_KORE_PORT", kore_port.to_string())
+            .env("NETREDIRECT_PING_INTERVAL", "50")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let commands = process.stdin.take().unwrap();
+        // Read on a thread of its own, so a client that never reports back
+        // fails the test rather than hanging it.
+        let (report, results) = mpsc::channel();
+        let stderr = BufReader::new(process.stderr.take().unwrap());
+        thread::spawn(move || stderr.lines().map_while(Result::ok).try_for_each(|line| report.send(line)));
+        Client { process, commands, results }
+    }
+
+    /// Runs `command` and returns what it reported.
+    fn run(&mut self, command: &str) -> String {
+        writeln!(self.commands, "{}", command).unwrap();
+        let name = command.split(' ').next().unwrap();
+        let line = self.results.recv_timeout(Duration::from_secs(5)).expect("no report from the client");
+        let result = line.strip_prefix(name).unwrap_or_else(|| panic!("`{}` reported `{}`", command, line));
+        result.trim().to_string()
+    }
+}
+
+/// Waits for the relay in the client to connect to Kore and bring the link
+/// up, which its first keep-alive shows.
+fn accept_kore(listener: &TcpListener) -> TcpStream {
+    let (mut kore, _) = listener.accept().unwrap();
+    kore.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut ping = [0u8; 3];
+    kore.read_exact(&mut ping).unwrap();
+    assert_eq!(XKoreFrame::decode(&ping), Some((XKoreFrame::KeepAlive, 3)));
+    kore
+}
+
+/// Has Kore inject `data` for the client, and waits until the relay has
+/// queued it: Kore's next frame, for the server, is only passed on after.
+fn inject(kore: &mut TcpStream, server: &mut TcpStream, data: &[u8]) {
+    let mut stream = XKoreFrame::Received(data.to_vec()).encode();
+    XKoreFrame::Sent(b"\xff\x00".to_vec()).encode_into(&mut stream);
+    kore.write_all(&stream).unwrap();
+    let mut marker = [0u8; 2];
+    server.read_exact(&mut marker).unwrap();
+    assert_eq!(&marker, b"\xff\x00");
+}
+
+#[test]
+fn relays_a_preloaded_client() {
+    let kore_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let ro_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = Client::start(kore_listener.local_addr().unwrap().port());
+
+    // The relay starts when the library is loaded.
+    let mut kore = accept_kore(&kore_listener);
+    let mut decoder = FrameDecoder::new();
+
+    client.run(&format!("connect {}", ro_listener.local_addr().unwrap().port()));
+    let (mut server, _) = ro_listener.accept().unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // What the server sends is read by the client and copied to Kore; what
+    // the client sends goes to Kore only. The client's stdin and stderr are
+    // not TCP sockets, so none of the commands and results are captured.
+    server.write_all(b"\x73\x00").unwrap();
+    assert_eq!(client.run("recv 16"), "7300");
+    client.run("send 7d00");
+    client.run("write 7e00");
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Sent(b"\x7d\x00".to_vec())));
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Sent(b"\x7e\x00".to_vec())));
+
+    // Data Kore injects is returned by recv and read, and left in place by a
+    // peek.
+    inject(&mut kore, &mut server, b"\x87\x00\x01\x02");
+    assert_eq!(client.run("peek 2"), "8700");
+    assert_eq!(client.run("recv 16"), "87000102");
+    inject(&mut kore, &mut server, b"\x88\x00");
+    assert_eq!(client.run("read 16"), "8800");
+
+    // A forked child has no relay, so it talks to the server directly.
+    assert_eq!(client.run("fork 9900"), "0");
+    let mut direct = [0u8; 2];
+    server.read_exact(&mut direct).unwrap();
+    assert_eq!(&direct, b"\x99\x00");
+
+    // The parent still relays, and what is queued for Kore when it exits is
+    // flushed before the relay disconnects.
+    client.run("send 7f00");
+    drop(client.commands);
+    wait_until("client exit", || client.process.try_wait().unwrap().is_some());
+    assert!(client.process.wait().unwrap().success());
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Sent(b"\x7f\x00".to_vec())));
+    assert_eq!(read_frame(&mut kore, &mut decoder), None);
+}
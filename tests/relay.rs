@@ -0,0 +1,620 @@
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect_when_listening, free_port, read_frame, start_relay, wait_until, RecordingHooks};
+use netredirect_rust::config::OverflowPolicy;
+use netredirect_rust::frame::FrameDecoder;
+use netredirect_rust::handshake;
+use netredirect_rust::relay::{self, RelayHandle};
+use netredirect_rust::state::{capture_recv, route_send, take_client_data, wait_for_kore_space, QueueDrops, RawSocket, SendRoute};
+use netredirect_rust::{Config, LinkState, NetworkState, SocketHooks, XKoreFrame};
+
+/// Starts a relay with the config `configure` sets up, accepts its
+/// connection and waits for the link.
+fn start(
+    hooks: Arc<RecordingHooks>,
+    configure: impl FnOnce(&mut Config),
+) -> (TcpStream, Arc<Mutex<NetworkState>>, RelayHandle) {
+    let (kore, state, relay) = start_relay(hooks, configure);
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+    (kore, state, relay)
+}
+
+#[test]
+fn relays_between_kore_and_hooks() {
+    let hooks = Arc::new(RecordingHooks::default());
+    let (mut kore, state, relay) = start(hooks.clone(), |_| {});
+
+    // Game traffic is reported to Kore.
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x7d\x00"), SendRoute::Kore);
+    let mut decoder = FrameDecoder::new();
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Sent(b"\x7d\x00".to_vec())));
+
+    // Kore's frames reach the server socket and the client, even when split.
+    let mut stream = XKoreFrame::Sent(b"\x7d\x00".to_vec()).encode();
+    XKoreFrame::Received(b"\x87\x00".to_vec()).encode_into(&mut stream);
+    kore.write_all(&stream[..4]).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    kore.write_all(&stream[4..]).unwrap();
+
+    wait_until("client data", || !state.lock().unwrap().client_data().is_empty());
+    let mut out = [0u8; 16];
+    assert_eq!(take_client_data(&mut state.lock().unwrap(), &mut out, false), 2);
+    assert_eq!(&out[..2], b"\x87\x00");
+    assert_eq!(*hooks.sent.lock().unwrap(), vec![(5, b"\x7d\x00".to_vec())]);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn shutdown_flushes_pending_frames() {
+    let (mut kore, state, relay) = start(Arc::default(), |_| {});
+
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x7d\x00"), SendRoute::Kore);
+    assert!(relay.shutdown(Duration::from_secs(5)));
+
+    let mut decoder = FrameDecoder::new();
+    assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Sent(b"\x7d\x00".to_vec())));
+    assert_eq!(kore.read(&mut [0u8; 16]).unwrap(), 0);
+    assert_eq!(state.lock().unwrap().link_state(), LinkState::Disconnected);
+}
+
+#[test]
+fn quiet_kore_does_not_block_the_game() {
+    let (_kore, state, relay) = start(Arc::default(), |_| {});
+
+    // Kore never writes anything, yet the hooks keep getting the lock promptly.
+    for _ in 0..20 {
+        let start = Instant::now();
+        capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+        assert!(start.elapsed() < Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn reconnects_after_kore_closes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = Config { kore_port: listener.local_addr().unwrap().port(), ..Config::default() };
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), Arc::new(RecordingHooks::default()));
+
+    let (kore, _) = listener.accept().unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+
+    drop(kore);
+    wait_until("disconnect", || state.lock().unwrap().link_state() == LinkState::Disconnected);
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x7d\x00"), SendRoute::Direct);
+
+    let (_kore, _) = listener.accept().unwrap();
+    wait_until("reconnect", || state.lock().unwrap().link_state() == LinkState::Connected);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    assert_eq!(state.lock().unwrap().link_state(), LinkState::Disconnected);
+}
+
+#[test]
+fn silent_kore_is_declared_dead() {
+    let (mut kore, state, relay) = start(Arc::default(), |config| {
+        config.ping_interval = Duration::from_millis(50);
+        config.ping_timeout = Duration::from_millis(300);
+    });
+
+    // Answering pings keeps the link up and measures the round trip.
+    let mut ping = [0u8; 3];
+    for _ in 0..10 {
+        kore.read_exact(&mut ping).unwrap();
+        assert_eq!(XKoreFrame::decode(&ping), Some((XKoreFrame::KeepAlive, 3)));
+        kore.write_all(&ping).unwrap();
+    }
+    assert_eq!(state.lock().unwrap().link_state(), LinkState::Connected);
+    assert!(state.lock().unwrap().kore_rtt().is_some());
+
+    // Going quiet does not.
+    wait_until("ping timeout", || state.lock().unwrap().link_state() == LinkState::Disconnected);
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x7d\x00"), SendRoute::Direct);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+/// Hooks whose sends to the RO server hang until the test lets them go.
+struct StalledServer {
+    entered: Mutex<mpsc::Sender<()>>,
+    release: Mutex<mpsc::Receiver<()>>,
+}
+
+impl SocketHooks for StalledServer {
+    fn send(&self, _socket: RawSocket, data: &[u8]) -> io::Result<usize> {
+        let _ = self.entered.lock().unwrap().send(());
+        let _ = self.release.lock().unwrap().recv();
+        Ok(data.len())
+    }
+}
+
+#[test]
+fn frames_from_a_lost_connection_are_dropped() {
+    let (entered, entered_rx) = mpsc::channel();
+    let (release, release_rx) = mpsc::channel();
+    let hooks = Arc::new(StalledServer { entered: Mutex::new(entered), release: Mutex::new(release_rx) });
+    let (mut kore, state, relay) = start_relay(hooks, |config| config.ping_timeout = Duration::from_millis(300));
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+
+    // The reader is held up passing the first frame on while the link
+    // times out; the second frame arrived on a connection that is gone.
+    let mut stream = XKoreFrame::Sent(b"\x7d\x00".to_vec()).encode();
+    XKoreFrame::Received(b"\x87\x00".to_vec()).encode_into(&mut stream);
+    kore.write_all(&stream).unwrap();
+    entered_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    wait_until("ping timeout", || state.lock().unwrap().link_state() != LinkState::Connected);
+    release.send(()).unwrap();
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(state.lock().unwrap().client_data().is_empty());
+
+    drop(release);
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn kore_that_stops_reading_is_given_up_on() {
+    let hooks = Arc::new(RecordingHooks::default());
+    let (_kore, state, relay) = start(hooks.clone(), |config| {
+        config.ping_timeout = Duration::from_millis(500);
+        config.kore_queue_limit = 1024 * 1024;
+    });
+
+    // Kore never reads, so the relay writes until the socket buffers are
+    // full and then stalls, leaving what is captured next in the queue.
+    let packet = vec![0x55u8; 60_000];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        capture_recv(&mut state.lock().unwrap(), 5, &packet);
+        let taken = Instant::now() + Duration::from_millis(100);
+        while state.lock().unwrap().kore_queued() > 0 && Instant::now() < taken {
+            thread::sleep(Duration::from_millis(1));
+        }
+        if state.lock().unwrap().kore_queued() > 0 {
+            break;
+        }
+        assert!(Instant::now() < deadline, "the relay never stalled");
+    }
+
+    // Overflowing the queue gives up on Kore, and what the client sends
+    // then reaches the server in spite of the stalled write.
+    while state.lock().unwrap().link_state() == LinkState::Connected {
+        capture_recv(&mut state.lock().unwrap(), 5, &packet);
+    }
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x7d\x00"), SendRoute::Held);
+    wait_until("pass-through", || hooks.server_data() == b"\x7d\x00");
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x7e\x00"), SendRoute::Direct);
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn stalled_write_does_not_outlast_the_ping_timeout() {
+    let (_kore, state, relay) = start(Arc::default(), |config| {
+        config.ping_timeout = Duration::from_millis(500);
+        config.kore_queue_limit = 64 * 1024 * 1024;
+    });
+    let connected = Instant::now();
+
+    // Kore neither reads nor answers. The relay only gets stuck writing to
+    // it shortly before it should be declared dead.
+    thread::sleep(Duration::from_millis(350));
+    let packet = vec![0x55u8; 60_000];
+    while state.lock().unwrap().link_state() == LinkState::Connected {
+        assert!(connected.elapsed() < Duration::from_secs(5), "Kore was never declared dead");
+        capture_recv(&mut state.lock().unwrap(), 5, &packet);
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(connected.elapsed() < Duration::from_millis(750), "dead after {:?}", connected.elapsed());
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn reconnect_now_skips_the_backoff() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = Config {
+        kore_port: listener.local_addr().unwrap().port(),
+        reconnect_delay: Duration::from_secs(60),
+        ..Config::default()
+    };
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), Arc::new(RecordingHooks::default()));
+
+    let (kore, _) = listener.accept().unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+    drop(kore);
+    wait_until("disconnect", || state.lock().unwrap().link_state() == LinkState::Disconnected);
+
+    relay.reconnect_now();
+    let (_kore, _) = listener.accept().unwrap();
+    wait_until("reconnect", || state.lock().unwrap().link_state() == LinkState::Connected);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+/// Starts a relay whose Kore queue fits two 2-byte frames.
+fn small_queue_relay(overflow: OverflowPolicy) -> (TcpStream, Arc<Mutex<NetworkState>>, Arc<RecordingHooks>, RelayHandle) {
+    let hooks = Arc::new(RecordingHooks::default());
+    let (kore, state, relay) = start(hooks.clone(), |config| {
+        config.kore_queue_limit = 10;
+        config.kore_overflow = overflow;
+    });
+    (kore, state, hooks, relay)
+}
+
+#[test]
+fn full_queue_drops_oldest_frames() {
+    let (_kore, state, _hooks, relay) = small_queue_relay(OverflowPolicy::DropOldest);
+
+    // Holding the lock keeps the relay from emptying the queue in between.
+    let mut locked = state.lock().unwrap();
+    capture_recv(&mut locked, 5, b"\x01\x00");
+    assert_eq!(route_send(&mut locked, 5, b"\x02\x00"), SendRoute::Kore);
+    capture_recv(&mut locked, 5, b"\x03\x00");
+    let queued: Vec<&[u8]> = locked.kore_frames().iter().map(XKoreFrame::payload).collect();
+    assert_eq!(queued, [b"\x02\x00", b"\x03\x00"]);
+    assert_eq!(locked.kore_dropped(), QueueDrops { frames: 1, bytes: 2 });
+    drop(locked);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn full_queue_drops_newest_frame() {
+    let (_kore, state, _hooks, relay) = small_queue_relay(OverflowPolicy::DropNewest);
+
+    let mut locked = state.lock().unwrap();
+    for packet in [b"\x01\x00", b"\x02\x00", b"\x03\x00"] {
+        capture_recv(&mut locked, 5, packet);
+    }
+    let queued: Vec<&[u8]> = locked.kore_frames().iter().map(XKoreFrame::payload).collect();
+    assert_eq!(queued, [b"\x01\x00", b"\x02\x00"]);
+    assert_eq!(locked.kore_queued(), 10);
+    assert_eq!(locked.kore_dropped(), QueueDrops { frames: 1, bytes: 2 });
+    drop(locked);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn full_queue_never_drops_client_data() {
+    for overflow in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+        let (_kore, state, hooks, relay) = small_queue_relay(overflow);
+
+        // Frames from the server make room for client data first.
+        let mut locked = state.lock().unwrap();
+        capture_recv(&mut locked, 5, b"\x73\x00");
+        assert_eq!(route_send(&mut locked, 5, b"\x01\x00"), SendRoute::Kore);
+        assert_eq!(route_send(&mut locked, 5, b"\x02\x00"), SendRoute::Kore);
+        assert_eq!(locked.kore_dropped(), QueueDrops { frames: 1, bytes: 2 });
+
+        // Once there is nothing left to drop, Kore is given up on instead.
+        assert_eq!(route_send(&mut locked, 5, b"\x03\x00"), SendRoute::Held);
+        assert_eq!(locked.link_state(), LinkState::Draining);
+        drop(locked);
+
+        wait_until("pass-through", || hooks.server_data().len() == 6);
+        assert_eq!(hooks.server_data(), b"\x01\x00\x02\x00\x03\x00", "{:?}", overflow);
+
+        assert!(relay.shutdown(Duration::from_secs(5)));
+    }
+}
+
+#[test]
+fn full_queue_passes_client_data_through() {
+    let (_kore, state, hooks, relay) = small_queue_relay(OverflowPolicy::Disconnect);
+
+    let mut locked = state.lock().unwrap();
+    assert_eq!(route_send(&mut locked, 5, b"\x01\x00"), SendRoute::Kore);
+    capture_recv(&mut locked, 5, b"\x73\x00");
+    assert_eq!(route_send(&mut locked, 5, b"\x02\x00"), SendRoute::Held);
+    assert_eq!(locked.link_state(), LinkState::Draining);
+    assert_eq!(locked.kore_dropped(), QueueDrops::default());
+    drop(locked);
+
+    // What the client sent before and after Kore was dropped reaches the
+    // server in order.
+    wait_until("pass-through", || hooks.server_data().len() == 4);
+    assert_eq!(hooks.server_data(), b"\x01\x00\x02\x00");
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x03\x00"), SendRoute::Direct);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn full_queue_blocks_the_game_until_written() {
+    let (mut kore, state, _hooks, relay) = small_queue_relay(OverflowPolicy::Block);
+
+    let mut locked = state.lock().unwrap();
+    assert_eq!(route_send(&mut locked, 5, b"\x01\x00"), SendRoute::Kore);
+    assert_eq!(route_send(&mut locked, 5, b"\x02\x00"), SendRoute::Kore);
+    drop(locked);
+
+    let game = {
+        let state = state.clone();
+        thread::spawn(move || route_send(&mut wait_for_kore_space(state.lock().unwrap()), 5, b"\x03\x00"))
+    };
+    assert_eq!(game.join().unwrap(), SendRoute::Kore);
+
+    let mut decoder = FrameDecoder::new();
+    for packet in [b"\x01\x00", b"\x02\x00", b"\x03\x00"] {
+        assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Sent(packet.to_vec())));
+    }
+    assert_eq!(state.lock().unwrap().kore_dropped(), QueueDrops::default());
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn large_reads_reach_kore_intact() {
+    let (mut kore, state, relay) = start(Arc::default(), |_| {});
+
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    capture_recv(&mut state.lock().unwrap(), 5, &data);
+
+    let mut decoder = FrameDecoder::new();
+    let mut received = Vec::new();
+    while received.len() < data.len() {
+        match read_frame(&mut kore, &mut decoder) {
+            Some(XKoreFrame::Received(part)) => received.extend(part),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+    assert_eq!(received, data);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[cfg(unix)]
+#[test]
+fn relays_over_unix_socket() {
+    use netredirect_rust::config::TransportKind;
+    use std::os::unix::net::UnixListener;
+
+    let path = std::env::temp_dir().join(format!("netredirect-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let config = Config {
+        kore_transport: TransportKind::Unix,
+        kore_path: path.clone(),
+        ..Config::default()
+    };
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), Arc::new(RecordingHooks::default()));
+
+    let (mut kore, _) = listener.accept().unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    kore.write_all(&XKoreFrame::Received(b"\x87\x00".to_vec()).encode()).unwrap();
+
+    let mut buf = [0u8; 5];
+    kore.read_exact(&mut buf).unwrap();
+    assert_eq!(XKoreFrame::decode(&buf), Some((XKoreFrame::Received(b"\x73\x00".to_vec()), 5)));
+    wait_until("client data", || state.lock().unwrap().client_data() == b"\x87\x00");
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn waits_for_kore_in_listen_mode() {
+    use netredirect_rust::config::TransportKind;
+
+    let port = free_port();
+    let config = Config {
+        kore_transport: TransportKind::Listen,
+        kore_port: port,
+        reconnect_delay: Duration::from_millis(10),
+        ..Config::default()
+    };
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let hooks = Arc::new(RecordingHooks::default());
+    let relay = relay::spawn(config, state.clone(), hooks.clone());
+
+    for round in 0..2 {
+        let mut kore = connect_when_listening(port);
+        wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+
+        capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(read_frame(&mut kore, &mut decoder), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+        kore.write_all(&XKoreFrame::Sent(b"\x7d\x00".to_vec()).encode()).unwrap();
+        wait_until("server data", || hooks.sent.lock().unwrap().len() == round + 1);
+
+        // The next Kore is accepted once this one has gone.
+        drop(kore);
+        wait_until("link loss", || state.lock().unwrap().link_state() == LinkState::Disconnected);
+    }
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn observers_get_a_copy_but_cannot_inject() {
+    let port = free_port();
+    let hooks = Arc::new(RecordingHooks::default());
+    let (mut kore, state, relay) = start(hooks.clone(), |config| config.observer_port = Some(port));
+    let mut observer = connect_when_listening(port);
+    wait_until("observer", || state.lock().unwrap().observers().len() == 1);
+
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(route_send(&mut state.lock().unwrap(), 5, b"\x7d\x00"), SendRoute::Kore);
+    for stream in [&mut kore, &mut observer] {
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(read_frame(stream, &mut decoder), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+        assert_eq!(read_frame(stream, &mut decoder), Some(XKoreFrame::Sent(b"\x7d\x00".to_vec())));
+    }
+
+    // What the observer sends goes nowhere, while Kore is still obeyed.
+    observer.write_all(&XKoreFrame::Sent(b"\x7d\x00".to_vec()).encode()).unwrap();
+    observer.write_all(&XKoreFrame::Received(b"\x87\x00".to_vec()).encode()).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(hooks.sent.lock().unwrap().is_empty());
+    assert!(state.lock().unwrap().client_data().is_empty());
+    kore.write_all(&XKoreFrame::Sent(b"\x7e\x00".to_vec()).encode()).unwrap();
+    wait_until("server data", || hooks.sent.lock().unwrap().len() == 1);
+
+    // Shutting down ends the observer's stream too.
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    assert_eq!(observer.read(&mut [0u8; 16]).unwrap(), 0);
+}
+
+#[test]
+fn observers_answer_the_challenge_when_a_secret_is_set() {
+    let port = free_port();
+    let (mut kore, state, relay) = start_relay(Arc::new(RecordingHooks::default()), |config| {
+        config.kore_secret = Some("s3cret".to_string());
+        config.observer_port = Some(port);
+    });
+    let mut decoder = FrameDecoder::new();
+    let answer = |stream: &mut TcpStream, decoder: &mut FrameDecoder, secret: &[u8]| match read_frame(stream, decoder) {
+        Some(XKoreFrame::Auth(challenge)) => {
+            stream.write_all(&XKoreFrame::Auth(handshake::respond(secret, &challenge)).encode()).unwrap();
+        }
+        other => panic!("expected a challenge, got {:?}", other),
+    };
+    answer(&mut kore, &mut decoder, b"s3cret");
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+
+    let mut intruder = connect_when_listening(port);
+    let mut intruder_decoder = FrameDecoder::new();
+    answer(&mut intruder, &mut intruder_decoder, b"guessed");
+    assert_eq!(read_frame(&mut intruder, &mut intruder_decoder), None);
+
+    let mut observer = connect_when_listening(port);
+    let mut observer_decoder = FrameDecoder::new();
+    answer(&mut observer, &mut observer_decoder, b"s3cret");
+    wait_until("observer", || state.lock().unwrap().observers().len() == 1);
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(read_frame(&mut observer, &mut observer_decoder), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+
+    // One that never answers is hung up on at shutdown.
+    let mut silent = connect_when_listening(port);
+    assert!(matches!(read_frame(&mut silent, &mut FrameDecoder::new()), Some(XKoreFrame::Auth(_))));
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    assert_eq!(silent.read(&mut [0u8; 16]).unwrap(), 0);
+}
+
+#[test]
+fn slow_observer_does_not_hold_up_kore() {
+    let port = free_port();
+    let (mut kore, state, relay) = start(Arc::default(), |config| {
+        config.observer_port = Some(port);
+        config.observer_queue_limit = 64 * 1024;
+        config.kore_overflow = OverflowPolicy::Block;
+    });
+    // The observer connects but never reads.
+    let _observer = connect_when_listening(port);
+    wait_until("observer", || state.lock().unwrap().observers().len() == 1);
+
+    let packet = vec![0x55u8; 32 * 1024];
+    let total = 256 * packet.len();
+    let reader = thread::spawn(move || {
+        let mut decoder = FrameDecoder::new();
+        let mut received = 0;
+        while received < total {
+            match read_frame(&mut kore, &mut decoder) {
+                Some(XKoreFrame::Received(data)) => received += data.len(),
+                other => panic!("unexpected frame {:?}", other),
+            }
+        }
+        received
+    });
+    for _ in 0..256 {
+        let mut locked = wait_for_kore_space(state.lock().unwrap());
+        capture_recv(&mut locked, 5, &packet);
+    }
+    assert_eq!(reader.join().unwrap(), total);
+
+    let dropped = state.lock().unwrap().observers()[0].dropped();
+    assert!(dropped.frames > 0, "{:?}", dropped);
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn fails_over_to_standby_and_back() {
+    use netredirect_rust::config::{Endpoint, FailbackPolicy};
+
+    let primary_port = free_port();
+    let standby = TcpListener::bind("127.0.0.1:0").unwrap();
+    let standby_port = standby.local_addr().unwrap().port();
+    let config = Config {
+        kore_port: primary_port,
+        kore_failover: vec![Endpoint::Tcp { host: "127.0.0.1".to_string(), port: standby_port }],
+        kore_failback: FailbackPolicy::After(Duration::from_millis(200)),
+        reconnect_delay: Duration::from_millis(10),
+        ..Config::default()
+    };
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), Arc::new(RecordingHooks::default()));
+
+    // Nothing listens on the primary, so the standby gets the link.
+    let (mut kore, _) = standby.accept().unwrap();
+    kore.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+    let expected = format!("tcp://127.0.0.1:{}", standby_port);
+    assert_eq!(state.lock().unwrap().kore_endpoint(), Some(expected.as_str()));
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(read_frame(&mut kore, &mut FrameDecoder::new()), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+
+    // Once the primary is back the standby is dropped for it.
+    let primary = TcpListener::bind(("127.0.0.1", primary_port)).unwrap();
+    let (_primary_kore, _) = primary.accept().unwrap();
+    assert_eq!(kore.read(&mut [0u8; 16]).unwrap(), 0);
+    let expected = format!("tcp://127.0.0.1:{}", primary_port);
+    wait_until("fail back", || state.lock().unwrap().kore_endpoint() == Some(expected.as_str()));
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+}
+
+#[test]
+fn idle_relay_wakes_for_traffic_and_shutdown() {
+    // Nothing is due for a minute, so only a signal can wake the relay.
+    let (mut kore, state, relay) = start(Arc::default(), |config| {
+        config.ping_interval = Duration::from_secs(60);
+        config.ping_timeout = Duration::from_secs(120);
+    });
+    kore.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_eq!(read_frame(&mut kore, &mut FrameDecoder::new()), Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+
+    let start = Instant::now();
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn relay_waiting_for_connections_shuts_down_promptly() {
+    use netredirect_rust::config::TransportKind;
+
+    // Kore and observers are waited for on threads blocked in accept, which
+    // shutdown has to wake.
+    let config = Config {
+        kore_transport: TransportKind::Listen,
+        kore_port: free_port(),
+        observer_port: Some(free_port()),
+        ..Config::default()
+    };
+    let relay = relay::spawn(config, Arc::new(Mutex::new(NetworkState::new())), Arc::new(RecordingHooks::default()));
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
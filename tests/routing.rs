@@ -0,0 +1,46 @@
+use netredirect_rust::state::{capture_recv, queue_client_data, route_send, take_client_data, SendRoute};
+use netredirect_rust::NetworkState;
+
+#[test]
+fn sends_go_direct_without_kore() {
+    let mut state = NetworkState::new();
+
+    assert_eq!(route_send(&mut state, 7, b"\x7d\x00"), SendRoute::Direct);
+    assert_eq!(state.ro_server(), Some(7));
+    assert!(state.kore_frames().is_empty());
+}
+
+#[test]
+fn recv_remembers_server_socket() {
+    let mut state = NetworkState::new();
+
+    capture_recv(&mut state, 3, b"\x73\x00");
+    assert_eq!(state.ro_server(), Some(3));
+    assert!(state.kore_frames().is_empty());
+}
+
+#[test]
+fn client_data_is_split_across_reads() {
+    let mut state = NetworkState::new();
+    queue_client_data(&mut state, &[1, 2, 3, 4, 5]);
+
+    let mut out = [0u8; 3];
+    assert_eq!(take_client_data(&mut state, &mut out, false), 3);
+    assert_eq!(out, [1, 2, 3]);
+    assert_eq!(state.client_data(), &[4, 5]);
+
+    let mut out = [0u8; 8];
+    assert_eq!(take_client_data(&mut state, &mut out, false), 2);
+    assert_eq!(&out[..2], &[4, 5]);
+    assert!(state.client_data().is_empty());
+}
+
+#[test]
+fn peek_leaves_client_data_queued() {
+    let mut state = NetworkState::new();
+    queue_client_data(&mut state, &[9, 8]);
+
+    let mut out = [0u8; 4];
+    assert_eq!(take_client_data(&mut state, &mut out, true), 2);
+    assert_eq!(state.client_data(), &[9, 8]);
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::crypto::ring;
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use common::{read_frame, wait_until, NoServer};
+use netredirect_rust::frame::FrameDecoder;
+use netredirect_rust::relay;
+use netredirect_rust::state::capture_recv;
+use netredirect_rust::{Config, LinkState, NetworkState, XKoreFrame};
+
+/// A TLS stand-in for Kore with a fresh self-signed certificate.
+struct TlsKore {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    cert_pem: String,
+}
+
+impl TlsKore {
+    fn new() -> Self {
+        let certified = rcgen::generate_simple_self_signed(vec!["openkore.test".to_string()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key)
+            .unwrap();
+
+        TlsKore {
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+            config: Arc::new(config),
+            cert_pem: certified.cert.pem(),
+        }
+    }
+
+    /// A relay config that pins `cert_pem`.
+    fn relay_config(&self, cert_pem: &str, name: &str) -> (Config, PathBuf) {
+        let path = std::env::temp_dir().join(format!("netredirect-{}-{}.pem", std::process::id(), name));
+        std::fs::write(&path, cert_pem).unwrap();
+        let config = Config {
+            kore_port: self.listener.local_addr().unwrap().port(),
+            kore_tls: true,
+            kore_tls_cert: Some(path.clone()),
+            ..Config::default()
+        };
+        (config, path)
+    }
+
+    fn accept(&self) -> io::Result<StreamOwned<ServerConnection, TcpStream>> {
+        let (sock, _) = self.listener.accept()?;
+        sock.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let conn = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        let mut stream = StreamOwned::new(conn, sock);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        Ok(stream)
+    }
+}
+
+#[test]
+fn relays_over_tls_with_pinned_certificate() {
+    let kore = TlsKore::new();
+    let (config, cert_path) = kore.relay_config(&kore.cert_pem, "pinned");
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), Arc::new(NoServer));
+
+    let mut stream = kore.accept().unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    let frame = read_frame(&mut stream, &mut FrameDecoder::new());
+    assert_eq!(frame, Some(XKoreFrame::Received(b"\x73\x00".to_vec())));
+
+    stream.write_all(&XKoreFrame::Received(b"\x87\x00".to_vec()).encode()).unwrap();
+    wait_until("client data", || state.lock().unwrap().client_data() == b"\x87\x00");
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    // The relay closes the session properly rather than just dropping the socket.
+    assert_eq!(stream.read(&mut [0u8; 16]).unwrap(), 0);
+    let _ = std::fs::remove_file(cert_path);
+}
+
+#[test]
+fn large_frames_arrive_whole_over_tls() {
+    let kore = TlsKore::new();
+    let (config, cert_path) = kore.relay_config(&kore.cert_pem, "large");
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), Arc::new(NoServer));
+
+    let mut stream = kore.accept().unwrap();
+    wait_until("link", || state.lock().unwrap().link_state() == LinkState::Connected);
+
+    // Several frames bigger than a TLS record, written in one go so they
+    // reach the relay in reads far larger than a record.
+    let packets: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 60_000]).collect();
+    let mut data = Vec::new();
+    for packet in &packets {
+        XKoreFrame::Received(packet.clone()).encode_into(&mut data);
+    }
+    stream.write_all(&data).unwrap();
+    let expected = packets.concat();
+    wait_until("client data", || state.lock().unwrap().client_data().len() >= expected.len());
+    assert!(state.lock().unwrap().client_data() == expected);
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    let _ = std::fs::remove_file(cert_path);
+}
+
+#[test]
+fn refuses_kore_with_other_certificate() {
+    let kore = TlsKore::new();
+    let other = rcgen::generate_simple_self_signed(vec!["openkore.test".to_string()]).unwrap();
+    let (config, cert_path) = kore.relay_config(&other.cert.pem(), "other");
+    let state = Arc::new(Mutex::new(NetworkState::new()));
+    let relay = relay::spawn(config, state.clone(), Arc::new(NoServer));
+
+    assert!(kore.accept().is_err());
+    capture_recv(&mut state.lock().unwrap(), 5, b"\x73\x00");
+    assert_ne!(state.lock().unwrap().link_state(), LinkState::Connected);
+    assert!(state.lock().unwrap().kore_frames().is_empty());
+
+    assert!(relay.shutdown(Duration::from_secs(5)));
+    let _ = std::fs::remove_file(cert_path);
+}